@@ -6,7 +6,7 @@ pub fn range_sum(c: &mut Criterion) {
     const KB: u32 = 1024;
     const MB: u32 = 1024 * KB;
 
-    let mut size = 1 * KB;
+    let mut size = KB;
     while size <= 32 * MB {
         let mut a = Vec::with_capacity(size as usize);
         for i in 0..size {
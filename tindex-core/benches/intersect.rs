@@ -1,5 +1,7 @@
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
-use tindex_core::{exclude, intersect, merge, PostingList, RangePostingList, NO_DOC};
+use tindex_core::{
+    exclude, intersect, intersect_n, merge, merge_n, PostingList, RangePostingList, NO_DOC,
+};
 
 pub fn posting_list_intersect(c: &mut Criterion) {
     let mut g = c.benchmark_group("Posting List Intersect");
@@ -88,6 +90,39 @@ pub fn posting_list_merge(c: &mut Criterion) {
     });
 }
 
+pub fn posting_list_n_ary(c: &mut Criterion) {
+    let mut g = c.benchmark_group("Posting List N-ary");
+    let lists = (0..40)
+        .map(|i| RangePostingList::new(1 + i * 10..1_000 + i * 10))
+        .collect::<Vec<_>>();
+    let all = || lists.iter().cloned().map(PostingList::from);
+
+    g.bench_function("Merge Chain", |bench| {
+        bench.iter_batched(
+            || all().reduce(merge).unwrap(),
+            traverse,
+            BatchSize::SmallInput,
+        );
+    });
+    g.bench_function("MergeN", |bench| {
+        bench.iter_batched(|| merge_n(all().collect()), traverse, BatchSize::SmallInput);
+    });
+    g.bench_function("Intersect Chain", |bench| {
+        bench.iter_batched(
+            || all().reduce(intersect).unwrap(),
+            traverse,
+            BatchSize::SmallInput,
+        );
+    });
+    g.bench_function("IntersectN", |bench| {
+        bench.iter_batched(
+            || intersect_n(all().collect()),
+            traverse,
+            BatchSize::SmallInput,
+        );
+    });
+}
+
 fn traverse(mut input: PostingList) {
     while input.next() != NO_DOC {}
 }
@@ -96,6 +131,7 @@ criterion_group!(
     benches,
     posting_list_intersect,
     posting_list_merge,
-    posting_list_exclude
+    posting_list_exclude,
+    posting_list_n_ary
 );
 criterion_main!(benches);
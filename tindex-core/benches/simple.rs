@@ -1,8 +1,5 @@
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
 
-#[derive(Clone)]
-struct PL([u64; 5]);
-
 pub fn range_sum(c: &mut Criterion) {
     let mut g = c.benchmark_group("Range Sum");
 
@@ -1,9 +1,21 @@
-use crate::{prelude::*, PostingListDecoder};
+use crate::{prelude::*, segment::Segment, DecodeError, PlBuffer, PostingListDecoder, NO_DOC};
+use anyhow::ensure;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
+use std::io::{self, BufRead, BufReader, Cursor, ErrorKind, Write};
 use std::path::Path;
 use std::str::FromStr;
 
+pub mod block;
+pub mod roaring;
+
+use block::BlockDecoder;
+use roaring::RoaringDecoder;
+
+/// Кодировщик posting list'а
+///
+/// Значения должны передаваться строго по возрастанию, быть положительными и не совпадать с [`NO_DOC`].
+/// Кодировщики проверяют эти инварианты ([`check_next`]) и возвращают ошибку [`ErrorKind::InvalidInput`]
+/// вместо записи файла, который не сможет быть корректно прочитан.
 pub trait Encoder {
     fn write_values(&mut self, values: impl Iterator<Item = u64>) -> IoResult<()> {
         for value in values {
@@ -13,38 +25,214 @@ pub trait Encoder {
     }
 
     fn write(&mut self, value: u64) -> IoResult<()>;
+
+    /// Завершает запись. Должен быть вызван после записи всех значений
+    fn finish(&mut self) -> IoResult<()> {
+        Ok(())
+    }
 }
 
-pub struct PlainTextEncoder(pub File);
+/// Проверяет, что `value` может быть записан в posting list следующим после `last`
+///
+/// `last` равный 0 означает, что в posting list еще не записано ни одного значения.
+pub fn check_next(last: u64, value: u64) -> IoResult<()> {
+    if value == 0 || value == NO_DOC {
+        let message = format!("Invalid identifier: {}", value);
+        Err(io::Error::new(ErrorKind::InvalidInput, message))
+    } else if value <= last {
+        let message = format!(
+            "Identifiers should be strictly increasing: {} after {}",
+            value, last
+        );
+        Err(io::Error::new(ErrorKind::InvalidInput, message))
+    } else {
+        Ok(())
+    }
+}
+
+pub struct PlainTextEncoder {
+    file: File,
+    last: u64,
+}
+
+impl PlainTextEncoder {
+    pub fn new(file: File) -> Self {
+        Self { file, last: 0 }
+    }
+
+    pub fn create(path: impl AsRef<Path>) -> IoResult<Self> {
+        Ok(Self::new(File::create(path)?))
+    }
+}
 
 impl Encoder for PlainTextEncoder {
     fn write(&mut self, value: u64) -> IoResult<()> {
-        writeln!(&mut self.0, "{}", value)
+        check_next(self.last, value)?;
+        self.last = value;
+        writeln!(&mut self.file, "{}", value)
     }
 }
 
-pub struct PlainTextDecoder(pub BufReader<File>);
+pub struct PlainTextDecoder<R: BufRead = BufReader<File>> {
+    source: R,
+    line: String,
+    last: u64,
+    error: Option<DecodeError>,
+}
 
 impl PlainTextDecoder {
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
-        Ok(Self(BufReader::new(File::open(path.as_ref())?)))
+        Ok(Self::new(BufReader::new(File::open(path.as_ref())?)))
+    }
+}
+
+impl<R: BufRead> PlainTextDecoder<R> {
+    pub fn new(source: R) -> Self {
+        Self {
+            source,
+            line: String::new(),
+            last: 0,
+            error: None,
+        }
+    }
+
+    /// Читает следующий идентификатор, возвращает `None` если файл закончился
+    fn read_next(&mut self) -> std::result::Result<Option<u64>, DecodeError> {
+        self.line.clear();
+        if self
+            .source
+            .read_line(&mut self.line)
+            .map_err(DecodeError::new)?
+            == 0
+        {
+            return Ok(None);
+        }
+        let line = self.line.trim_end();
+        let value = u64::from_str(line)
+            .map_err(|_| DecodeError(format!("Invalid identifier: {:?}", line)))?;
+        check_next(self.last, value).map_err(DecodeError::new)?;
+        self.last = value;
+        Ok(Some(value))
     }
 }
 
-impl PostingListDecoder for PlainTextDecoder {
-    fn next_batch(&mut self, buffer: &mut crate::PlBuffer) -> usize {
-        let mut line = String::new();
+impl<R: BufRead + Send> PostingListDecoder for PlainTextDecoder<R> {
+    fn next_batch(&mut self, buffer: &mut PlBuffer) -> usize {
+        if self.error.is_some() {
+            return 0;
+        }
         for (i, item) in buffer.iter_mut().enumerate() {
-            line.clear();
-            let result = self.0.read_line(&mut line).unwrap();
-            if result == 0 {
-                return i;
-            } else {
-                *item = u64::from_str(line.trim_end()).unwrap()
+            match self.read_next() {
+                Ok(Some(value)) => *item = value,
+                Ok(None) => return i,
+                Err(error) => {
+                    self.error = Some(error);
+                    return i;
+                }
             }
         }
         buffer.len()
     }
+
+    fn error(&self) -> Option<&DecodeError> {
+        self.error.as_ref()
+    }
+}
+
+/// Декодер индексного файла произвольного формата
+///
+/// Формат определяется по магической последовательности в начале файла. Файлы без известной
+/// магической последовательности читаются как [`PlainTextDecoder`]. Все форматы читаются из отображенного
+/// в память [`Segment`]'а.
+#[allow(clippy::large_enum_variant)]
+pub enum FileDecoder {
+    PlainText(PlainTextDecoder<Cursor<Segment>>),
+    Block(BlockDecoder<Segment>),
+    Roaring(RoaringDecoder<Cursor<Segment>>),
+}
+
+impl FileDecoder {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::new(Segment::open(path)?)
+    }
+
+    pub fn new(segment: Segment) -> Result<Self> {
+        let decoder = match segment.as_ref().get(0..4) {
+            Some(m) if m == block::MAGIC => Self::Block(BlockDecoder::new(segment)?),
+            Some(m) if m == roaring::MAGIC => {
+                Self::Roaring(RoaringDecoder::new(Cursor::new(segment))?)
+            }
+            _ => Self::PlainText(PlainTextDecoder::new(Cursor::new(segment))),
+        };
+        Ok(decoder)
+    }
+
+    /// Полностью декодирует индексный файл и возвращает количество идентификаторов в нем
+    ///
+    /// Помимо контрольных сумм, которые проверяются декодером, проверяется что идентификаторы строго
+    /// возрастают и их количество совпадает с указанным в заголовке.
+    pub fn verify(segment: Segment) -> Result<u64> {
+        let expected = Self::new(segment.clone())?.count();
+        let mut decoder = Self::new(segment)?;
+        let mut buffer = [0; 128];
+        let (mut count, mut last) = (0, 0);
+        loop {
+            let len = decoder.next_batch(&mut buffer);
+            if len == 0 {
+                break;
+            }
+            for value in &buffer[..len] {
+                check_next(last, *value).map_err(DecodeError::new)?;
+                last = *value;
+            }
+            count += len as u64;
+        }
+        if let Some(error) = decoder.error() {
+            return Err(error.clone().into());
+        }
+        ensure!(
+            count == expected,
+            DecodeError(format!(
+                "{} identifiers expected, {} found",
+                expected, count
+            ))
+        );
+        Ok(count)
+    }
+}
+
+impl PostingListDecoder for FileDecoder {
+    fn next_batch_advance(&mut self, target: u64, buffer: &mut PlBuffer) -> usize {
+        match self {
+            Self::PlainText(d) => d.next_batch_advance(target, buffer),
+            Self::Block(d) => d.next_batch_advance(target, buffer),
+            Self::Roaring(d) => d.next_batch_advance(target, buffer),
+        }
+    }
+
+    fn next_batch(&mut self, buffer: &mut PlBuffer) -> usize {
+        match self {
+            Self::PlainText(d) => d.next_batch(buffer),
+            Self::Block(d) => d.next_batch(buffer),
+            Self::Roaring(d) => d.next_batch(buffer),
+        }
+    }
+
+    fn count(&mut self) -> u64 {
+        match self {
+            Self::PlainText(d) => d.count(),
+            Self::Block(d) => d.count(),
+            Self::Roaring(d) => d.count(),
+        }
+    }
+
+    fn error(&self) -> Option<&DecodeError> {
+        match self {
+            Self::PlainText(d) => d.error(),
+            Self::Block(d) => d.error(),
+            Self::Roaring(d) => d.error(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -57,7 +245,7 @@ mod tests {
         let dir = tempdir()?;
         let path = dir.path().join("plaintext.txt");
 
-        let mut text = PlainTextEncoder(File::create(&path)?);
+        let mut text = PlainTextEncoder::create(&path)?;
         text.write_values(1..10)?;
 
         let result = PlainTextDecoder::open(&path)?.to_vec();
@@ -65,4 +253,145 @@ mod tests {
         assert_eq!(result, (1..10).collect::<Vec<_>>());
         Ok(())
     }
+
+    #[test]
+    fn check_file_format_detection() -> Result<()> {
+        let dir = tempdir()?;
+        let plain = dir.path().join("plain.idx");
+        let block = dir.path().join("block.idx");
+        let roaring = dir.path().join("roaring.idx");
+
+        PlainTextEncoder::create(&plain)?.write_values(1..10)?;
+        let mut encoder = block::BlockEncoder::create(&block)?;
+        encoder.write_values(1..10)?;
+        encoder.finish()?;
+        let mut encoder = roaring::RoaringEncoder::create(&roaring)?;
+        encoder.write_values(1..10)?;
+        encoder.finish()?;
+
+        for path in [plain, block, roaring] {
+            let result = FileDecoder::open(&path)?.to_vec();
+            assert_eq!(result, (1..10).collect::<Vec<_>>());
+        }
+
+        let empty = dir.path().join("empty.idx");
+        File::create(&empty)?;
+        assert!(FileDecoder::open(&empty)?.to_vec().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn check_invalid_values_rejected() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("invalid.idx");
+
+        fn check(encoder: &mut impl Encoder) {
+            for value in [0, NO_DOC] {
+                let error = encoder.write(value).unwrap_err();
+                assert_eq!(error.kind(), ErrorKind::InvalidInput);
+            }
+            encoder.write(5).unwrap();
+            // дубликаты и нарушение порядка
+            for value in [5, 4] {
+                let error = encoder.write(value).unwrap_err();
+                assert_eq!(error.kind(), ErrorKind::InvalidInput);
+            }
+            encoder.write(6).unwrap();
+            encoder.finish().unwrap();
+        }
+
+        check(&mut PlainTextEncoder::create(&path)?);
+        assert_eq!(FileDecoder::open(&path)?.to_vec(), vec![5, 6]);
+        check(&mut block::BlockEncoder::create(&path)?);
+        assert_eq!(FileDecoder::open(&path)?.to_vec(), vec![5, 6]);
+        check(&mut roaring::RoaringEncoder::create(&path)?);
+        assert_eq!(FileDecoder::open(&path)?.to_vec(), vec![5, 6]);
+        Ok(())
+    }
+
+    #[test]
+    fn check_corrupted_files() -> Result<()> {
+        let dir = tempdir()?;
+        let plain = dir.path().join("plain.idx");
+        let block = dir.path().join("block.idx");
+        let roaring = dir.path().join("roaring.idx");
+
+        std::fs::write(&plain, "1\n2\nfoo\n4\n")?;
+        let mut encoder = block::BlockEncoder::create(&block)?;
+        encoder.write_values(1..1000)?;
+        encoder.finish()?;
+        let mut encoder = roaring::RoaringEncoder::create(&roaring)?;
+        encoder.write_values((1..1_000_000).step_by(3))?;
+        encoder.finish()?;
+
+        // ширина упаковки первого блока, который следует сразу за 24-байтовым заголовком
+        let mut data = std::fs::read(&block)?;
+        data[24] = 200;
+        std::fs::write(&block, data)?;
+        // усечение последнего контейнера
+        let data = std::fs::read(&roaring)?;
+        std::fs::write(&roaring, &data[..data.len() - 10])?;
+
+        for path in [plain, block, roaring] {
+            let result = FileDecoder::open(&path)?.try_to_vec();
+            assert!(result.is_err(), "{}", path.display());
+
+            // ошибка распространяется через составные списки
+            let vec = crate::VecPostingList::new(&[1, 2, 3]);
+            let mut list = crate::merge(FileDecoder::open(&path)?.into(), vec.into());
+            while list.next() != NO_DOC {}
+            assert!(list.check().is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn check_checksums() -> Result<()> {
+        let dir = tempdir()?;
+        let block = dir.path().join("block.idx");
+        let roaring = dir.path().join("roaring.idx");
+
+        let values = (1..10_000).step_by(7).collect::<Vec<_>>();
+        let mut encoder = block::BlockEncoder::create(&block)?;
+        encoder.write_values(values.iter().copied())?;
+        encoder.finish()?;
+        let mut encoder = roaring::RoaringEncoder::create(&roaring)?;
+        encoder.write_values(values.iter().copied())?;
+        encoder.finish()?;
+
+        for path in [block, roaring] {
+            assert_eq!(FileDecoder::open(&path)?.try_to_vec()?, values);
+
+            // искажаем один бит в данных первого блока (контейнера)
+            let mut data = std::fs::read(&path)?;
+            data[40] ^= 1;
+            std::fs::write(&path, data)?;
+            let error = FileDecoder::open(&path)?.try_to_vec().unwrap_err();
+            assert!(error.0.contains("checksum"), "{}", error);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn check_verify() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("plain.idx");
+
+        std::fs::write(&path, "1\n2\n3\n")?;
+        assert_eq!(FileDecoder::verify(Segment::open(&path)?)?, 3);
+
+        std::fs::write(&path, "1\n3\n2\n")?;
+        let error = FileDecoder::verify(Segment::open(&path)?).unwrap_err();
+        assert!(error.is::<DecodeError>());
+
+        // заголовок обещает больше элементов, чем записано
+        let mut encoder = block::BlockEncoder::create(&path)?;
+        encoder.write_values(1..10)?;
+        encoder.finish()?;
+        let mut data = std::fs::read(&path)?;
+        data[8] = 10;
+        std::fs::write(&path, data)?;
+        assert!(FileDecoder::verify(Segment::open(&path)?).is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,561 @@
+//! Бинарный формат хранения posting list'ов
+//!
+//! Файл состоит из заголовка, последовательности блоков и таблицы пропусков (skip table). Заголовок содержит
+//! магическую последовательность [`MAGIC`], версию формата [`VERSION`], общее количество идентификаторов в списке
+//! и смещение таблицы пропусков от начала файла (все числа – little endian).
+//!
+//! Каждый блок содержит до [`BLOCK_SIZE`] идентификаторов, записанных в виде разниц (дельт) с предыдущим
+//! значением. Первая дельта блока отсчитывается от последнего значения предыдущего блока (или от 0 для первого блока).
+//! Дельты упаковываются с одинаковой для всего блока битовой шириной:
+//!
+//! ```text
+//! +-----------+------------------------------------------+---------------+
+//! | width: u8 | packed deltas: ceil(len * width / 8) bytes | checksum: u32 |
+//! +-----------+------------------------------------------+---------------+
+//! ```
+//!
+//! `checksum` – CRC32 ширины и упакованных дельт. Контрольная сумма проверяется при чтении каждого блока,
+//! поэтому повреждение файла обнаруживается декодером ([`PostingListDecoder::error`]), а не приводит к
+//! выдаче произвольных идентификаторов. Файлы версии 2 не содержат контрольных сумм и читаются без проверки.
+//!
+//! Длина блока не хранится: все блоки, кроме последнего, содержат ровно [`BLOCK_SIZE`] значений,
+//! а длина последнего вычисляется из количества элементов в заголовке.
+//!
+//! Таблица пропусков содержит для каждого блока пару `(last: u64, offset: u64)` – последний идентификатор блока
+//! и смещение блока от начала файла. Она позволяет выполнять [`PostingListDecoder::next_batch_advance`]
+//! двоичным поиском нужного блока, не декодируя предшествующие ему блоки.
+use crate::{prelude::*, segment::Segment, DecodeError, PlBuffer, PostingListDecoder};
+use anyhow::ensure;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::{check_next, Encoder};
+
+pub const MAGIC: &[u8; 4] = b"TIDX";
+pub const VERSION: u32 = 3;
+pub const BLOCK_SIZE: usize = 128;
+
+const HEADER_SIZE: usize = 24;
+const SKIP_SIZE: usize = 16;
+const CHECKSUM_SIZE: usize = 4;
+
+/// Последняя версия формата без контрольных сумм блоков
+const UNCHECKED_VERSION: u32 = 2;
+
+/// Пишет отсортированный список идентификаторов в бинарном блочном формате
+///
+/// Так как количество элементов становится известно только в конце записи, заголовок перезаписывается
+/// в [`Encoder::finish`]. Без вызова `finish` файл остается невалидным.
+pub struct BlockEncoder<W: Write + Seek> {
+    sink: BufWriter<W>,
+    block: Vec<u64>,
+    packed: Vec<u8>,
+    skips: Vec<Skip>,
+    count: u64,
+    last: u64,
+    /// смещение от начала файла, по которому будет записан следующий блок
+    offset: u64,
+}
+
+/// Запись таблицы пропусков: последний идентификатор блока и смещение блока от начала файла
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Skip {
+    last: u64,
+    offset: u64,
+}
+
+impl<W: Write + Seek> BlockEncoder<W> {
+    pub fn new(sink: W) -> IoResult<Self> {
+        let mut sink = BufWriter::new(sink);
+        write_header(&mut sink, 0, 0)?;
+        Ok(Self {
+            sink,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            packed: Vec::with_capacity(BLOCK_SIZE * 8),
+            skips: vec![],
+            count: 0,
+            last: 0,
+            offset: HEADER_SIZE as u64,
+        })
+    }
+
+    fn flush_block(&mut self) -> IoResult<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let width = self
+            .block
+            .iter()
+            .map(|delta| u64::BITS - delta.leading_zeros())
+            .max()
+            .unwrap_or(0);
+        self.packed.clear();
+        pack(&self.block, width, &mut self.packed);
+        let mut checksum = crc32fast::Hasher::new();
+        checksum.update(&[width as u8]);
+        checksum.update(&self.packed);
+        self.sink.write_all(&[width as u8])?;
+        self.sink.write_all(&self.packed)?;
+        self.sink.write_all(&checksum.finalize().to_le_bytes())?;
+        self.skips.push(Skip {
+            last: self.last,
+            offset: self.offset,
+        });
+        self.offset += (1 + self.packed.len() + CHECKSUM_SIZE) as u64;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+impl BlockEncoder<File> {
+    pub fn create(path: impl AsRef<Path>) -> IoResult<Self> {
+        Self::new(File::create(path)?)
+    }
+}
+
+impl<W: Write + Seek> Encoder for BlockEncoder<W> {
+    fn write(&mut self, value: u64) -> IoResult<()> {
+        check_next(self.last, value)?;
+        self.block.push(value.wrapping_sub(self.last));
+        self.last = value;
+        self.count += 1;
+        if self.block.len() == BLOCK_SIZE {
+            self.flush_block()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> IoResult<()> {
+        self.flush_block()?;
+        for skip in &self.skips {
+            self.sink.write_all(&skip.last.to_le_bytes())?;
+            self.sink.write_all(&skip.offset.to_le_bytes())?;
+        }
+        self.sink.seek(SeekFrom::Start(0))?;
+        write_header(&mut self.sink, self.count, self.offset)?;
+        self.sink.seek(SeekFrom::End(0))?;
+        self.sink.flush()
+    }
+}
+
+/// Читает posting list записанный [`BlockEncoder`]'ом
+///
+/// Декодер работает поверх последовательности байт (например, отображенного в память [`Segment`]'а)
+/// и не копирует ни блоки, ни таблицу пропусков: при исчерпании текущего блока следующий блок
+/// распаковывается целиком непосредственно из источника.
+pub struct BlockDecoder<S: AsRef<[u8]>> {
+    source: S,
+    count: u64,
+    skips_offset: usize,
+    blocks: usize,
+    /// номер блока, который будет прочитан следующим
+    next_block: usize,
+    block: [u64; BLOCK_SIZE],
+    block_len: usize,
+    position: usize,
+    last: u64,
+    /// содержат ли блоки контрольные суммы (зависит от версии формата)
+    checksums: bool,
+    error: Option<DecodeError>,
+}
+
+impl BlockDecoder<Segment> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::new(Segment::open(path)?)
+    }
+}
+
+impl<S: AsRef<[u8]>> BlockDecoder<S> {
+    pub fn new(source: S) -> Result<Self> {
+        let (version, count, skips_offset) = read_header(source.as_ref())?;
+        let blocks = count.div_ceil(BLOCK_SIZE as u64) as usize;
+        let skips_end = skips_offset
+            .checked_add(blocks as u64 * SKIP_SIZE as u64)
+            .filter(|end| *end <= source.as_ref().len() as u64);
+        ensure!(skips_end.is_some(), "Posting list file is truncated");
+        Ok(Self {
+            source,
+            count,
+            skips_offset: skips_offset as usize,
+            blocks,
+            next_block: 0,
+            block: [0; BLOCK_SIZE],
+            block_len: 0,
+            position: 0,
+            last: 0,
+            checksums: version > UNCHECKED_VERSION,
+            error: None,
+        })
+    }
+
+    fn skip(&self, block: usize) -> Skip {
+        let offset = self.skips_offset + block * SKIP_SIZE;
+        let entry = &self.source.as_ref()[offset..offset + SKIP_SIZE];
+        Skip {
+            last: u64::from_le_bytes(entry[0..8].try_into().unwrap()),
+            offset: u64::from_le_bytes(entry[8..16].try_into().unwrap()),
+        }
+    }
+
+    /// Читает следующий блок. При повреждении данных запоминает ошибку и считает список исчерпанным
+    fn read_block(&mut self) -> usize {
+        if self.next_block >= self.blocks {
+            return 0;
+        }
+        match self.decode_block() {
+            Ok(len) => len,
+            Err(error) => {
+                self.error = Some(error);
+                self.next_block = self.blocks;
+                0
+            }
+        }
+    }
+
+    fn decode_block(&mut self) -> std::result::Result<usize, DecodeError> {
+        let block = self.next_block;
+        let corrupted = || DecodeError(format!("Block {} is truncated or corrupted", block));
+
+        let decoded = (block * BLOCK_SIZE) as u64;
+        let len = (self.count - decoded).min(BLOCK_SIZE as u64) as usize;
+        let offset = self.skip(block).offset as usize;
+        let data = self.source.as_ref().get(offset..).ok_or_else(corrupted)?;
+        let width = *data.first().ok_or_else(corrupted)? as u32;
+        if width > u64::BITS {
+            return Err(corrupted());
+        }
+        let end = 1 + packed_len(len, width);
+        let packed = data.get(1..end).ok_or_else(corrupted)?;
+        if self.checksums {
+            let expected = data.get(end..end + CHECKSUM_SIZE).ok_or_else(corrupted)?;
+            if crc32fast::hash(&data[..end]).to_le_bytes() != expected {
+                return Err(DecodeError(format!("Block {} checksum mismatch", block)));
+            }
+        }
+
+        unpack(packed, width, &mut self.block[..len]);
+        for item in &mut self.block[..len] {
+            self.last = self.last.wrapping_add(*item);
+            *item = self.last;
+        }
+        if self.last != self.skip(block).last {
+            return Err(DecodeError(format!(
+                "Block {} doesn't match skip table",
+                block
+            )));
+        }
+        self.next_block += 1;
+        Ok(len)
+    }
+
+    /// Переходит к блоку с номером `block`, так чтобы он был прочитан следующим
+    fn seek_block(&mut self, block: usize) {
+        if block != self.next_block {
+            self.last = if block > 0 {
+                self.skip(block - 1).last
+            } else {
+                0
+            };
+            self.next_block = block;
+        }
+    }
+
+    /// Возвращает номер первого из непрочитанных блоков, последний элемент которого не меньше `target`
+    fn find_block(&self, target: u64) -> usize {
+        let (mut lo, mut hi) = (self.next_block, self.blocks);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.skip(mid).last < target {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+}
+
+impl<S: AsRef<[u8]> + Send> PostingListDecoder for BlockDecoder<S> {
+    fn next_batch_advance(&mut self, target: u64, buffer: &mut PlBuffer) -> usize {
+        let in_current_block =
+            self.position < self.block_len && self.block[self.block_len - 1] >= target;
+        if !in_current_block {
+            let block = self.find_block(target);
+            if block >= self.blocks {
+                self.next_block = self.blocks;
+                self.block_len = 0;
+                self.position = 0;
+                return 0;
+            }
+            self.seek_block(block);
+            self.block_len = self.read_block();
+            self.position = 0;
+        }
+        self.position += self.block[self.position..self.block_len].partition_point(|v| *v < target);
+        self.next_batch(buffer)
+    }
+
+    fn next_batch(&mut self, buffer: &mut PlBuffer) -> usize {
+        if self.position >= self.block_len {
+            self.block_len = self.read_block();
+            self.position = 0;
+        }
+        let len = buffer.len().min(self.block_len - self.position);
+        buffer[..len].copy_from_slice(&self.block[self.position..self.position + len]);
+        self.position += len;
+        len
+    }
+
+    /// Количество элементов вычисляется из заголовка без декодирования оставшихся блоков
+    fn count(&mut self) -> u64 {
+        let buffered = (self.block_len - self.position) as u64;
+        let unread = self
+            .count
+            .saturating_sub((self.next_block * BLOCK_SIZE) as u64);
+        self.next_block = self.blocks;
+        self.position = self.block_len;
+        buffered + unread
+    }
+
+    fn error(&self) -> Option<&DecodeError> {
+        self.error.as_ref()
+    }
+}
+
+fn write_header(sink: &mut impl Write, count: u64, skips_offset: u64) -> IoResult<()> {
+    sink.write_all(MAGIC)?;
+    sink.write_all(&VERSION.to_le_bytes())?;
+    sink.write_all(&count.to_le_bytes())?;
+    sink.write_all(&skips_offset.to_le_bytes())
+}
+
+/// Читает и проверяет заголовок файла, возвращает версию, количество элементов и смещение таблицы пропусков
+fn read_header(data: &[u8]) -> Result<(u32, u64, u64)> {
+    ensure!(data.len() >= HEADER_SIZE, "Posting list file is truncated");
+    let header = &data[..HEADER_SIZE];
+    ensure!(&header[0..4] == MAGIC, "Invalid posting list file magic");
+
+    let version = u32::from_le_bytes(header[4..8].try_into()?);
+    ensure!(
+        version == VERSION || version == UNCHECKED_VERSION,
+        "Unsupported posting list format version: {}",
+        version
+    );
+    let count = u64::from_le_bytes(header[8..16].try_into()?);
+    let skips_offset = u64::from_le_bytes(header[16..24].try_into()?);
+    Ok((version, count, skips_offset))
+}
+
+#[inline]
+fn packed_len(len: usize, width: u32) -> usize {
+    (len * width as usize).div_ceil(8)
+}
+
+/// Упаковывает значения используя `width` младших бит каждого из них
+fn pack(values: &[u64], width: u32, out: &mut Vec<u8>) {
+    if width == 0 {
+        return;
+    }
+    let mut acc: u128 = 0;
+    let mut bits = 0;
+    for value in values {
+        acc |= (*value as u128) << bits;
+        bits += width;
+        while bits >= 8 {
+            out.push(acc as u8);
+            acc >>= 8;
+            bits -= 8;
+        }
+    }
+    if bits > 0 {
+        out.push(acc as u8);
+    }
+}
+
+/// Распаковывает `out.len()` значений шириной `width` бит, упакованных функцией [`pack`]
+fn unpack(packed: &[u8], width: u32, out: &mut [u64]) {
+    if width == 0 {
+        out.fill(0);
+        return;
+    }
+    let mask = u64::MAX >> (u64::BITS - width);
+    let mut bytes = packed.iter();
+    let mut acc: u128 = 0;
+    let mut bits = 0;
+    for item in out.iter_mut() {
+        while bits < width {
+            acc |= (*bytes.next().unwrap() as u128) << bits;
+            bits += 8;
+        }
+        *item = acc as u64 & mask;
+        acc >>= width;
+        bits -= width;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PostingList, NO_DOC};
+    use rand::prelude::*;
+    use std::io::Cursor;
+    use tempfile::tempdir;
+
+    #[test]
+    fn check_block_readwrite() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("block.idx");
+
+        let mut encoder = BlockEncoder::create(&path)?;
+        encoder.write_values(1..1000)?;
+        encoder.finish()?;
+
+        let result = BlockDecoder::open(&path)?.to_vec();
+
+        assert_eq!(result, (1..1000).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn check_block_boundaries() -> Result<()> {
+        for len in [
+            0,
+            1,
+            BLOCK_SIZE - 1,
+            BLOCK_SIZE,
+            BLOCK_SIZE + 1,
+            3 * BLOCK_SIZE,
+        ] {
+            let values = (1..=len as u64).map(|i| i * 3).collect::<Vec<_>>();
+            assert_eq!(roundtrip(&values)?, values);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn check_block_wide_deltas() -> Result<()> {
+        let values = vec![1, 2, 1 << 32, (1 << 32) + 1, u64::MAX - 1];
+        assert_eq!(roundtrip(&values)?, values);
+        Ok(())
+    }
+
+    #[test]
+    fn check_block_random() -> Result<()> {
+        let mut rng = thread_rng();
+        for _ in 0..20 {
+            let mut value = 0;
+            let values = (0..rng.gen_range(1..2000))
+                .map(|_| {
+                    let bits = rng.gen_range(1..40);
+                    value += rng.gen_range(1..1u64 << bits);
+                    value
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(roundtrip(&values)?, values);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn check_block_advance() -> Result<()> {
+        let values = (1..10_000).map(|i| i * 2).collect::<Vec<_>>();
+        let mut decoder = decoder(&values)?;
+        let mut buffer = [0; 4];
+
+        assert_eq!(decoder.next_batch(&mut buffer), 4);
+        assert_eq!(buffer, [2, 4, 6, 8]);
+
+        // внутри текущего блока
+        assert_eq!(decoder.next_batch_advance(15, &mut buffer), 4);
+        assert_eq!(buffer, [16, 18, 20, 22]);
+
+        // через несколько блоков
+        assert_eq!(decoder.next_batch_advance(5001, &mut buffer), 4);
+        assert_eq!(buffer, [5002, 5004, 5006, 5008]);
+
+        // назад advance не двигается
+        assert_eq!(decoder.next_batch_advance(1, &mut buffer), 4);
+        assert_eq!(buffer, [5010, 5012, 5014, 5016]);
+
+        assert_eq!(decoder.next_batch_advance(19_997, &mut buffer), 1);
+        assert_eq!(buffer[0], 19_998);
+
+        assert_eq!(decoder.next_batch_advance(20_000, &mut buffer), 0);
+        assert_eq!(decoder.next_batch(&mut buffer), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn check_block_advance_random() -> Result<()> {
+        let mut rng = thread_rng();
+        for _ in 0..20 {
+            let mut value = 0;
+            let values = (0..rng.gen_range(1..5000))
+                .map(|_| {
+                    value += rng.gen_range(1..100);
+                    value
+                })
+                .collect::<Vec<_>>();
+            let mut list = PostingList::from(decoder(&values)?);
+
+            let mut target = 0;
+            while target <= value {
+                target += rng.gen_range(1..500);
+                let expected = values
+                    .iter()
+                    .find(|v| **v >= target)
+                    .cloned()
+                    .unwrap_or(NO_DOC);
+                assert_eq!(list.advance(target), expected);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn check_block_count() -> Result<()> {
+        let values = (1..1000).map(|i| i * 2).collect::<Vec<_>>();
+        assert_eq!(decoder(&values)?.count(), values.len() as u64);
+
+        for target in [1, 100, 255, 256, 257, 1500, 1998, 1999] {
+            let mut list = PostingList::from(decoder(&values)?);
+            list.advance(target);
+            let expected = values.iter().filter(|v| **v >= target).count();
+            assert_eq!(list.count(), expected as u64, "{}", target);
+            assert_eq!(list.next(), NO_DOC);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn check_invalid_magic() {
+        let data = b"NOTANINDEXFILE..........".to_vec();
+        assert!(BlockDecoder::new(data).is_err());
+    }
+
+    #[test]
+    fn check_truncated_file() -> Result<()> {
+        let mut encoder = BlockEncoder::new(Cursor::new(vec![]))?;
+        encoder.write_values(1..1000)?;
+        encoder.finish()?;
+        let mut data = encoder.sink.into_inner()?.into_inner();
+        data.truncate(data.len() - 1);
+
+        assert!(BlockDecoder::new(data).is_err());
+        Ok(())
+    }
+
+    fn roundtrip(values: &[u64]) -> Result<Vec<u64>> {
+        Ok(decoder(values)?.to_vec())
+    }
+
+    fn decoder(values: &[u64]) -> Result<BlockDecoder<Vec<u8>>> {
+        let mut encoder = BlockEncoder::new(Cursor::new(vec![]))?;
+        encoder.write_values(values.iter().cloned())?;
+        encoder.finish()?;
+        let data = encoder.sink.into_inner()?.into_inner();
+
+        BlockDecoder::new(data)
+    }
+}
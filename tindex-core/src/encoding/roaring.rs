@@ -0,0 +1,819 @@
+//! Формат хранения плотных posting list'ов в стиле [Roaring Bitmap](https://roaringbitmap.org)
+//!
+//! Пространство идентификаторов разбивается на чанки по 65536 значений. Идентификаторы каждого непустого чанка
+//! хранятся в отдельном контейнере, тип которого выбирается при записи так, чтобы контейнер занимал
+//! наименьший объем:
+//!
+//! - [`ARRAY`] – отсортированный массив младших 16 бит идентификаторов (`u16` на элемент);
+//! - [`BITMAP`] – битовая карта на 65536 бит (8 КБ независимо от количества элементов);
+//! - [`RUN`] – список непрерывных интервалов `(start: u16, length - 1: u16)`.
+//!
+//! Файл начинается с заголовка: магическая последовательность [`MAGIC`], версия [`VERSION`] и общее количество
+//! идентификаторов. Далее следуют контейнеры в порядке возрастания чанков (все числа – little endian):
+//!
+//! ```text
+//! +----------+----------+-------------------+---------+
+//! | key: u64 | kind: u8 | cardinality: u32  | payload |
+//! +----------+----------+-------------------+---------+
+//! ```
+//!
+//! `key` – старшие 48 бит идентификаторов чанка. Для [`RUN`] контейнера payload начинается с количества
+//! интервалов (`u16`), для остальных типов размер payload вычисляется из типа и количества элементов.
+//!
+//! За payload следует CRC32 заголовка и payload контейнера (`u32`). Контрольная сумма проверяется при
+//! загрузке контейнера, контейнеры, пропускаемые без декодирования, не проверяются. Файлы версии 1
+//! не содержат контрольных сумм и читаются без проверки.
+//!
+//! Независимо от версии декодер проверяет, что ключи контейнеров и значения внутри контейнера строго
+//! возрастают, а количество значений совпадает с `cardinality`.
+use crate::{prelude::*, DecodeError, PlBuffer, PostingListDecoder};
+use anyhow::{bail, ensure, Context};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::{check_next, Encoder};
+
+pub const MAGIC: &[u8; 4] = b"TRBM";
+pub const VERSION: u32 = 2;
+
+/// Последняя версия формата без контрольных сумм контейнеров
+const UNCHECKED_VERSION: u32 = 1;
+const CHECKSUM_SIZE: usize = 4;
+
+pub const ARRAY: u8 = 0;
+pub const BITMAP: u8 = 1;
+pub const RUN: u8 = 2;
+
+const HEADER_SIZE: usize = 16;
+const CHUNK_BITS: u32 = 16;
+const CHUNK_SIZE: u32 = 1 << CHUNK_BITS;
+const BITMAP_WORDS: usize = CHUNK_SIZE as usize / 64;
+const BITMAP_BYTES: usize = BITMAP_WORDS * 8;
+
+/// Пишет posting list в контейнерном формате, выбирая тип контейнера для каждого чанка автоматически
+pub struct RoaringEncoder<W: Write + Seek> {
+    sink: BufWriter<W>,
+    key: u64,
+    chunk: Vec<u16>,
+    /// сериализованный контейнер, для которого вычисляется контрольная сумма
+    bytes: Vec<u8>,
+    count: u64,
+    last: u64,
+}
+
+impl<W: Write + Seek> RoaringEncoder<W> {
+    pub fn new(sink: W) -> IoResult<Self> {
+        let mut sink = BufWriter::new(sink);
+        write_header(&mut sink, 0)?;
+        Ok(Self {
+            sink,
+            key: 0,
+            chunk: Vec::with_capacity(CHUNK_SIZE as usize),
+            bytes: vec![],
+            count: 0,
+            last: 0,
+        })
+    }
+
+    fn flush_chunk(&mut self) -> IoResult<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+        let runs = runs(&self.chunk);
+        let kind = choose_container(self.chunk.len(), runs.len());
+
+        let bytes = &mut self.bytes;
+        bytes.clear();
+        bytes.extend_from_slice(&self.key.to_le_bytes());
+        bytes.extend_from_slice(&[kind]);
+        bytes.extend_from_slice(&(self.chunk.len() as u32).to_le_bytes());
+        match kind {
+            ARRAY => {
+                for low in &self.chunk {
+                    bytes.extend_from_slice(&low.to_le_bytes());
+                }
+            }
+            BITMAP => {
+                let mut words = [0u64; BITMAP_WORDS];
+                for low in &self.chunk {
+                    words[*low as usize / 64] |= 1 << (low % 64);
+                }
+                for word in words {
+                    bytes.extend_from_slice(&word.to_le_bytes());
+                }
+            }
+            _ => {
+                bytes.extend_from_slice(&(runs.len() as u16).to_le_bytes());
+                for (start, length) in runs {
+                    bytes.extend_from_slice(&start.to_le_bytes());
+                    bytes.extend_from_slice(&length.to_le_bytes());
+                }
+            }
+        }
+        let checksum = crc32fast::hash(bytes);
+        self.sink.write_all(bytes)?;
+        self.sink.write_all(&checksum.to_le_bytes())?;
+        self.chunk.clear();
+        Ok(())
+    }
+}
+
+impl RoaringEncoder<File> {
+    pub fn create(path: impl AsRef<Path>) -> IoResult<Self> {
+        Self::new(File::create(path)?)
+    }
+}
+
+impl<W: Write + Seek> Encoder for RoaringEncoder<W> {
+    fn write(&mut self, value: u64) -> IoResult<()> {
+        check_next(self.last, value)?;
+        self.last = value;
+        let key = value >> CHUNK_BITS;
+        if key != self.key {
+            self.flush_chunk()?;
+            self.key = key;
+        }
+        self.chunk.push(value as u16);
+        self.count += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> IoResult<()> {
+        self.flush_chunk()?;
+        self.sink.seek(SeekFrom::Start(0))?;
+        write_header(&mut self.sink, self.count)?;
+        self.sink.seek(SeekFrom::End(0))?;
+        self.sink.flush()
+    }
+}
+
+/// Выбирает тип контейнера, занимающий наименьший объем
+fn choose_container(cardinality: usize, runs: usize) -> u8 {
+    let array = 2 * cardinality;
+    let run = 2 + 4 * runs;
+    if run < array && run < BITMAP_BYTES {
+        RUN
+    } else if array <= BITMAP_BYTES {
+        ARRAY
+    } else {
+        BITMAP
+    }
+}
+
+/// Разбивает отсортированный чанк на непрерывные интервалы `(start, length - 1)`
+fn runs(chunk: &[u16]) -> Vec<(u16, u16)> {
+    let mut runs: Vec<(u16, u16)> = vec![];
+    for low in chunk {
+        match runs.last_mut() {
+            Some((start, length)) if *start as u32 + *length as u32 + 1 == *low as u32 => {
+                *length += 1
+            }
+            _ => runs.push((*low, 0)),
+        }
+    }
+    runs
+}
+
+enum Container {
+    Array(Vec<u16>),
+    Bitmap(Vec<u64>),
+    Run(Vec<(u16, u16)>),
+}
+
+impl Container {
+    /// Проверяет, что значения контейнера строго возрастают и их ровно `cardinality`
+    fn check(&self, cardinality: u32) -> Result<()> {
+        match self {
+            Container::Array(values) => {
+                let position = values.windows(2).position(|w| w[0] >= w[1]);
+                ensure!(
+                    position.is_none(),
+                    "Array values are not strictly increasing at {}",
+                    position.unwrap_or(0) + 1
+                );
+            }
+            Container::Bitmap(words) => {
+                let count = words.iter().map(|w| w.count_ones()).sum::<u32>();
+                ensure!(
+                    count == cardinality,
+                    "Bitmap cardinality mismatch: {} expected, {} found",
+                    cardinality,
+                    count
+                );
+            }
+            Container::Run(runs) => {
+                let (mut count, mut next) = (0u32, 0u32);
+                for (start, length) in runs {
+                    let (start, end) = (*start as u32, *start as u32 + *length as u32);
+                    ensure!(
+                        end < CHUNK_SIZE,
+                        "Run {}+{} exceeds container range",
+                        start,
+                        length
+                    );
+                    ensure!(
+                        start >= next,
+                        "Runs are unordered or overlapping at {}",
+                        start
+                    );
+                    count += *length as u32 + 1;
+                    next = end + 1;
+                }
+                ensure!(
+                    count == cardinality,
+                    "Run container cardinality mismatch: {} expected, {} found",
+                    cardinality,
+                    count
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Читает posting list записанный [`RoaringEncoder`]'ом
+///
+/// В памяти находится только текущий контейнер. При выполнении [`PostingListDecoder::next_batch_advance`]
+/// контейнеры предшествующих чанков пропускаются без декодирования.
+pub struct RoaringDecoder<R: Read> {
+    source: R,
+    /// количество значений в контейнерах, которые еще не были прочитаны из источника
+    remaining: u64,
+    key: u64,
+    /// ключ последнего прочитанного заголовка контейнера, в том числе пропущенного
+    last_key: Option<u64>,
+    container: Container,
+    /// позиция в контейнере: индекс в массиве, номер бита в битовой карте или индекс интервала
+    index: usize,
+    /// смещение внутри текущего интервала (только для [`RUN`] контейнеров)
+    offset: u32,
+    /// контрольная сумма прочитанной части текущего контейнера
+    checksum: Option<crc32fast::Hasher>,
+    error: Option<DecodeError>,
+}
+
+impl RoaringDecoder<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::new(BufReader::new(File::open(path.as_ref())?))
+    }
+}
+
+impl<R: Read> RoaringDecoder<R> {
+    pub fn new(mut source: R) -> Result<Self> {
+        let (version, count) = read_header(&mut source)?;
+        Ok(Self {
+            source,
+            remaining: count,
+            key: 0,
+            last_key: None,
+            container: Container::Array(vec![]),
+            index: 0,
+            offset: 0,
+            checksum: (version > UNCHECKED_VERSION).then(crc32fast::Hasher::new),
+            error: None,
+        })
+    }
+
+    /// Читает заголовок следующего контейнера, возвращает `(key, kind, cardinality)`
+    fn read_container_header(&mut self) -> Result<Option<(u64, u8, u32)>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        let mut header = [0u8; 13];
+        self.source.read_exact(&mut header)?;
+        if let Some(checksum) = &mut self.checksum {
+            checksum.reset();
+            checksum.update(&header);
+        }
+        let key = u64::from_le_bytes(header[0..8].try_into()?);
+        let cardinality = u32::from_le_bytes(header[9..13].try_into()?);
+        ensure!(
+            cardinality > 0 && cardinality as u64 <= self.remaining,
+            "Invalid container cardinality: {}",
+            cardinality
+        );
+        ensure!(
+            key <= u64::MAX >> CHUNK_BITS,
+            "Invalid container key: {}",
+            key
+        );
+        ensure!(
+            self.last_key < Some(key),
+            "Container keys are not strictly increasing: {} after {:?}",
+            key,
+            self.last_key
+        );
+        self.last_key = Some(key);
+        self.remaining -= cardinality as u64;
+        Ok(Some((key, header[8], cardinality)))
+    }
+
+    fn read_container(&mut self, kind: u8, cardinality: u32) -> Result<Container> {
+        let container = match kind {
+            ARRAY => {
+                let bytes = self.read_bytes(2 * cardinality as usize)?;
+                let values = bytes
+                    .chunks_exact(2)
+                    .map(|b| u16::from_le_bytes([b[0], b[1]]))
+                    .collect();
+                Container::Array(values)
+            }
+            BITMAP => {
+                let bytes = self.read_bytes(BITMAP_BYTES)?;
+                let words = bytes
+                    .chunks_exact(8)
+                    .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+                    .collect();
+                Container::Bitmap(words)
+            }
+            RUN => {
+                let runs = self.read_run_count()?;
+                let bytes = self.read_bytes(4 * runs)?;
+                let runs = bytes
+                    .chunks_exact(4)
+                    .map(|b| {
+                        let start = u16::from_le_bytes([b[0], b[1]]);
+                        let length = u16::from_le_bytes([b[2], b[3]]);
+                        (start, length)
+                    })
+                    .collect();
+                Container::Run(runs)
+            }
+            _ => bail!("Unknown container type: {}", kind),
+        };
+        if let Some(checksum) = &self.checksum {
+            let expected = checksum.clone().finalize();
+            let mut actual = [0u8; CHECKSUM_SIZE];
+            self.source.read_exact(&mut actual)?;
+            ensure!(
+                u32::from_le_bytes(actual) == expected,
+                "Container {} checksum mismatch",
+                self.key
+            );
+        }
+        container
+            .check(cardinality)
+            .with_context(|| format!("Container {}", self.key))?;
+        Ok(container)
+    }
+
+    /// Пропускает payload контейнера не декодируя его
+    fn skip_container(&mut self, kind: u8, cardinality: u32) -> Result<()> {
+        let len = match kind {
+            ARRAY => 2 * cardinality as usize,
+            BITMAP => BITMAP_BYTES,
+            RUN => 4 * self.read_run_count()?,
+            _ => bail!("Unknown container type: {}", kind),
+        };
+        let len = if self.checksum.is_some() {
+            len + CHECKSUM_SIZE
+        } else {
+            len
+        };
+        io::copy(&mut (&mut self.source).take(len as u64), &mut io::sink())?;
+        Ok(())
+    }
+
+    fn read_run_count(&mut self) -> Result<usize> {
+        let runs = self.read_bytes(2)?;
+        Ok(u16::from_le_bytes([runs[0], runs[1]]) as usize)
+    }
+
+    fn read_bytes(&mut self, len: usize) -> IoResult<Vec<u8>> {
+        let mut bytes = vec![0; len];
+        self.source.read_exact(&mut bytes)?;
+        if let Some(checksum) = &mut self.checksum {
+            checksum.update(&bytes);
+        }
+        Ok(bytes)
+    }
+
+    /// Загружает первый из непрочитанных контейнеров с ключом не меньше `key`.
+    ///
+    /// Возвращает `false` если такого контейнера нет
+    fn load_container(&mut self, key: u64) -> Result<bool> {
+        while let Some((next_key, kind, cardinality)) = self.read_container_header()? {
+            if next_key < key {
+                self.skip_container(kind, cardinality)?;
+            } else {
+                self.key = next_key;
+                self.container = self.read_container(kind, cardinality)?;
+                self.index = 0;
+                self.offset = 0;
+                return Ok(true);
+            }
+        }
+        self.container = Container::Array(vec![]);
+        self.index = 0;
+        Ok(false)
+    }
+
+    /// Аналог [`Self::load_container`], который при повреждении данных запоминает ошибку и считает
+    /// список исчерпанным
+    fn try_load_container(&mut self, key: u64) -> bool {
+        match self.load_container(key) {
+            Ok(loaded) => loaded,
+            Err(error) => {
+                self.error = Some(DecodeError(format!("{:#}", error)));
+                self.container = Container::Array(vec![]);
+                self.index = 0;
+                self.remaining = 0;
+                false
+            }
+        }
+    }
+
+    /// Заполняет буфер значениями текущего контейнера
+    fn fill(&mut self, buffer: &mut PlBuffer) -> usize {
+        let high = self.key << CHUNK_BITS;
+        let mut i = 0;
+        match &self.container {
+            Container::Array(values) => {
+                let len = buffer
+                    .len()
+                    .min(values.len() - self.index.min(values.len()));
+                for (item, low) in buffer[..len].iter_mut().zip(&values[self.index..]) {
+                    *item = high | *low as u64;
+                }
+                self.index += len;
+                i = len;
+            }
+            Container::Bitmap(words) => {
+                while i < buffer.len() && self.index < CHUNK_SIZE as usize {
+                    let word = words[self.index / 64] >> (self.index % 64);
+                    if word == 0 {
+                        self.index = (self.index / 64 + 1) * 64;
+                        continue;
+                    }
+                    self.index += word.trailing_zeros() as usize;
+                    buffer[i] = high | self.index as u64;
+                    i += 1;
+                    self.index += 1;
+                }
+            }
+            Container::Run(runs) => {
+                while i < buffer.len() && self.index < runs.len() {
+                    let (start, length) = runs[self.index];
+                    buffer[i] = high | (start as u64 + self.offset as u64);
+                    i += 1;
+                    if self.offset == length as u32 {
+                        self.index += 1;
+                        self.offset = 0;
+                    } else {
+                        self.offset += 1;
+                    }
+                }
+            }
+        }
+        i
+    }
+
+    /// Количество непрочитанных значений в текущем контейнере
+    fn container_remaining(&self) -> u64 {
+        match &self.container {
+            Container::Array(values) => values.len().saturating_sub(self.index) as u64,
+            Container::Bitmap(words) => {
+                let word = self.index / 64;
+                if word >= words.len() {
+                    return 0;
+                }
+                let current = (words[word] >> (self.index % 64)).count_ones();
+                let rest = words[word + 1..]
+                    .iter()
+                    .map(|w| w.count_ones())
+                    .sum::<u32>();
+                (current + rest) as u64
+            }
+            Container::Run(runs) => {
+                let total = runs
+                    .iter()
+                    .skip(self.index)
+                    .map(|(_, length)| *length as u64 + 1)
+                    .sum::<u64>();
+                total.saturating_sub(self.offset as u64)
+            }
+        }
+    }
+
+    /// Проверяет остались ли в текущем контейнере непрочитанные значения
+    fn exhausted(&self) -> bool {
+        match &self.container {
+            Container::Array(values) => self.index >= values.len(),
+            Container::Run(runs) => self.index >= runs.len(),
+            Container::Bitmap(words) => {
+                let word = self.index / 64;
+                word >= words.len()
+                    || (words[word] >> (self.index % 64) == 0
+                        && words[word + 1..].iter().all(|w| *w == 0))
+            }
+        }
+    }
+
+    /// Сдвигает позицию в текущем контейнере на первый элемент не меньший `low`
+    fn seek_low(&mut self, low: u32) {
+        match &self.container {
+            Container::Array(values) => {
+                let position = values.partition_point(|v| (*v as u32) < low);
+                self.index = self.index.max(position);
+            }
+            Container::Bitmap(_) => {
+                self.index = self.index.max(low as usize);
+            }
+            Container::Run(runs) => {
+                let position =
+                    runs.partition_point(|(start, length)| (*start as u32 + *length as u32) < low);
+                if position > self.index {
+                    self.index = position;
+                    self.offset = 0;
+                }
+                if let Some((start, _)) = runs.get(self.index) {
+                    let start = *start as u32;
+                    if start + self.offset < low {
+                        self.offset = low - start;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read + Send> PostingListDecoder for RoaringDecoder<R> {
+    fn next_batch(&mut self, buffer: &mut PlBuffer) -> usize {
+        loop {
+            let len = self.fill(buffer);
+            if len > 0 {
+                return len;
+            }
+            if !self.try_load_container(0) {
+                return 0;
+            }
+        }
+    }
+
+    fn next_batch_advance(&mut self, target: u64, buffer: &mut PlBuffer) -> usize {
+        let key = target >> CHUNK_BITS;
+        if (self.key < key || self.exhausted()) && !self.try_load_container(key) {
+            return 0;
+        }
+        if self.key == key {
+            self.seek_low(target as u32 & (CHUNK_SIZE - 1));
+        }
+        self.next_batch(buffer)
+    }
+
+    /// Количество элементов непрочитанных контейнеров известно из их заголовков, а для битовых карт
+    /// текущего контейнера вычисляется подсчетом установленных бит
+    fn count(&mut self) -> u64 {
+        let count = self.container_remaining() + self.remaining;
+        self.container = Container::Array(vec![]);
+        self.index = 0;
+        self.remaining = 0;
+        count
+    }
+
+    fn error(&self) -> Option<&DecodeError> {
+        self.error.as_ref()
+    }
+}
+
+fn write_header(sink: &mut impl Write, count: u64) -> IoResult<()> {
+    sink.write_all(MAGIC)?;
+    sink.write_all(&VERSION.to_le_bytes())?;
+    sink.write_all(&count.to_le_bytes())
+}
+
+/// Читает и проверяет заголовок файла, возвращает версию формата и количество элементов
+fn read_header(source: &mut impl Read) -> Result<(u32, u64)> {
+    let mut header = [0u8; HEADER_SIZE];
+    source.read_exact(&mut header)?;
+    ensure!(&header[0..4] == MAGIC, "Invalid posting list file magic");
+
+    let version = u32::from_le_bytes(header[4..8].try_into()?);
+    ensure!(
+        version == VERSION || version == UNCHECKED_VERSION,
+        "Unsupported posting list format version: {}",
+        version
+    );
+    Ok((version, u64::from_le_bytes(header[8..16].try_into()?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PostingList, NO_DOC};
+    use rand::prelude::*;
+    use std::io::Cursor;
+    use tempfile::tempdir;
+
+    #[test]
+    fn check_roaring_readwrite() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("roaring.idx");
+
+        let mut encoder = RoaringEncoder::create(&path)?;
+        encoder.write_values(1..200_000)?;
+        encoder.finish()?;
+
+        let result = RoaringDecoder::open(&path)?.to_vec();
+
+        assert_eq!(result, (1..200_000).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn check_container_choice() {
+        // разреженный чанк
+        assert_eq!(choose_container(10, 10), ARRAY);
+        // плотный чанк без длинных интервалов
+        assert_eq!(choose_container(30_000, 20_000), BITMAP);
+        // непрерывный интервал
+        assert_eq!(choose_container(65_536, 1), RUN);
+    }
+
+    #[test]
+    fn check_roaring_containers() -> Result<()> {
+        let mut values = vec![];
+        // array
+        values.extend((1..100).map(|i| i * 100));
+        // bitmap
+        values.extend(
+            (0..CHUNK_SIZE as u64)
+                .filter(|i| i % 3 == 0)
+                .map(|i| (1 << 16) + i),
+        );
+        // run
+        values.extend((2 << 16) + 10..(3 << 16) + 50);
+        values.extend([u64::MAX - 1]);
+
+        assert_eq!(decoder(&values)?.to_vec(), values);
+        Ok(())
+    }
+
+    #[test]
+    fn check_roaring_count() -> Result<()> {
+        let mut values = vec![];
+        values.extend((1..100).map(|i| i * 100));
+        values.extend(
+            (0..CHUNK_SIZE as u64)
+                .filter(|i| i % 3 == 0)
+                .map(|i| (1 << 16) + i),
+        );
+        values.extend((2 << 16) + 10..(3 << 16) + 50);
+        assert_eq!(decoder(&values)?.count(), values.len() as u64);
+
+        // позиции внутри контейнеров каждого из типов
+        for target in [1, 5_000, (1 << 16) + 1000, (2 << 16) + 20, (3 << 16) + 10] {
+            let mut list = PostingList::from(decoder(&values)?);
+            list.advance(target);
+            let expected = values.iter().filter(|v| **v >= target).count();
+            assert_eq!(list.count(), expected as u64, "{}", target);
+            assert_eq!(list.next(), NO_DOC);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn check_roaring_advance_random() -> Result<()> {
+        let mut rng = thread_rng();
+        for _ in 0..20 {
+            let density = rng.gen_range(1..200);
+            let mut value = 0;
+            let values = (0..rng.gen_range(1..50_000))
+                .map(|_| {
+                    value += if rng.gen_bool(0.001) {
+                        rng.gen_range(1..1 << 20)
+                    } else {
+                        rng.gen_range(1..density + 1)
+                    };
+                    value
+                })
+                .collect::<Vec<_>>();
+            let mut list = PostingList::from(decoder(&values)?);
+
+            let mut target = 0;
+            while target <= value {
+                target += rng.gen_range(1..50_000);
+                let expected = values
+                    .iter()
+                    .find(|v| **v >= target)
+                    .cloned()
+                    .unwrap_or(NO_DOC);
+                assert_eq!(list.advance(target), expected);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn check_invalid_runs() -> Result<()> {
+        let file = |runs: &[(u16, u16)], cardinality| file(&[(0, RUN, cardinality, run(runs))]);
+
+        assert_eq!(
+            file(&[(1, 2), (10, 0)], 4)?.try_to_vec()?,
+            vec![1, 2, 3, 10]
+        );
+        assert_eq!(
+            file(&[(0, u16::MAX)], CHUNK_SIZE)?.count(),
+            CHUNK_SIZE as u64
+        );
+
+        // выход за границу чанка
+        assert!(file(&[(u16::MAX, 1)], 2)?.try_to_vec().is_err());
+        // пересекающиеся и неупорядоченные интервалы
+        assert!(file(&[(1, 5), (3, 0)], 7)?.try_to_vec().is_err());
+        assert!(file(&[(10, 0), (1, 0)], 2)?.try_to_vec().is_err());
+        // количество значений не совпадает с заголовком
+        assert!(file(&[(1, 5)], 3)?.try_to_vec().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn check_invalid_arrays() -> Result<()> {
+        let file = |values: &[u16]| file(&[(0, ARRAY, values.len() as u32, array(values))]);
+
+        assert_eq!(file(&[1, 3, 7])?.try_to_vec()?, vec![1, 3, 7]);
+        assert!(file(&[1, 7, 3])?.try_to_vec().is_err());
+        assert!(file(&[1, 3, 3])?.try_to_vec().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn check_invalid_bitmaps() -> Result<()> {
+        let file = |cardinality| file(&[(0, BITMAP, cardinality, bitmap(&[1, 3, 7]))]);
+
+        assert_eq!(file(3)?.try_to_vec()?, vec![1, 3, 7]);
+        assert!(file(2)?.try_to_vec().is_err());
+        assert!(file(4)?.try_to_vec().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn check_invalid_keys() -> Result<()> {
+        let file = |keys: &[u64]| {
+            let containers = keys
+                .iter()
+                .map(|key| (*key, ARRAY, 1, array(&[5])))
+                .collect::<Vec<_>>();
+            file(&containers)
+        };
+
+        assert_eq!(file(&[0, 2])?.try_to_vec()?, vec![5, (2 << 16) + 5]);
+        assert!(file(&[2, 0])?.try_to_vec().is_err());
+        assert!(file(&[2, 2])?.try_to_vec().is_err());
+        assert!(file(&[1 << 48])?.try_to_vec().is_err());
+
+        // ключи пропускаемых контейнеров также проверяются
+        let mut list = PostingList::from(file(&[3, 1, 4])?);
+        assert_eq!(list.advance(4 << 16), NO_DOC);
+        assert!(list.check().is_err());
+        Ok(())
+    }
+
+    /// Файл из контейнеров `(key, kind, cardinality, payload)` с корректными контрольными суммами
+    fn file(containers: &[(u64, u8, u32, Vec<u8>)]) -> Result<RoaringDecoder<Cursor<Vec<u8>>>> {
+        let count = containers.iter().map(|c| c.2 as u64).sum();
+        let mut data = vec![];
+        write_header(&mut data, count)?;
+        for (key, kind, cardinality, payload) in containers {
+            let start = data.len();
+            data.extend_from_slice(&key.to_le_bytes());
+            data.push(*kind);
+            data.extend_from_slice(&cardinality.to_le_bytes());
+            data.extend_from_slice(payload);
+            let checksum = crc32fast::hash(&data[start..]);
+            data.extend_from_slice(&checksum.to_le_bytes());
+        }
+        RoaringDecoder::new(Cursor::new(data))
+    }
+
+    fn array(values: &[u16]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    fn bitmap(values: &[u16]) -> Vec<u8> {
+        let mut words = [0u64; BITMAP_WORDS];
+        for v in values {
+            words[*v as usize / 64] |= 1 << (v % 64);
+        }
+        words.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    fn run(runs: &[(u16, u16)]) -> Vec<u8> {
+        let mut payload = (runs.len() as u16).to_le_bytes().to_vec();
+        for (start, length) in runs {
+            payload.extend_from_slice(&start.to_le_bytes());
+            payload.extend_from_slice(&length.to_le_bytes());
+        }
+        payload
+    }
+
+    fn decoder(values: &[u64]) -> Result<RoaringDecoder<Cursor<Vec<u8>>>> {
+        let mut encoder = RoaringEncoder::new(Cursor::new(vec![]))?;
+        encoder.write_values(values.iter().cloned())?;
+        encoder.finish()?;
+        let data = encoder.sink.into_inner()?.into_inner();
+
+        RoaringDecoder::new(Cursor::new(data))
+    }
+}
@@ -1,6 +1,9 @@
-use std::ops::Range;
+use std::{cmp::Reverse, collections::BinaryHeap, ops::Range};
+use thiserror::Error;
 
 pub mod encoding;
+pub mod segment;
+pub mod sort;
 
 mod prelude {
     pub type Result<T> = anyhow::Result<T>;
@@ -10,7 +13,26 @@ mod prelude {
 pub const NO_DOC: u64 = u64::MAX;
 type PlBuffer = [u64];
 
-pub trait PostingListDecoder {
+/// Ошибка чтения поврежденного или усеченного индексного файла
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Corrupted posting list: {0}")]
+pub struct DecodeError(pub String);
+
+impl DecodeError {
+    pub fn new(message: impl ToString) -> Self {
+        Self(message.to_string())
+    }
+}
+
+/// Источник отсортированных идентификаторов документов
+///
+/// Декодеры должны быть [`Send`], чтобы [`PostingList`] можно было передать в другой поток, например,
+/// для потоковой отдачи результата запроса.
+///
+/// Чтение выполняется в горячем цикле, поэтому методы чтения не возвращают `Result`. Декодер, обнаруживший
+/// повреждение данных, запоминает ошибку и далее ведет себя как исчерпанный. После чтения потребитель должен
+/// проверить, что список не был прерван ошибкой ([`PostingListDecoder::error`], [`PostingList::check`]).
+pub trait PostingListDecoder: Send {
     fn next_batch_advance(&mut self, target: u64, buffer: &mut PlBuffer) -> usize {
         let mut len = self.next_batch(buffer);
         if len == 0 {
@@ -27,6 +49,50 @@ pub trait PostingListDecoder {
 
     fn next_batch(&mut self, buffer: &mut PlBuffer) -> usize;
 
+    /// Возвращает количество оставшихся элементов, исчерпывая декодер
+    ///
+    /// Реализация по умолчанию декодирует все элементы. Декодеры, которым количество элементов известно
+    /// заранее (например, из заголовка файла), должны переопределять этот метод.
+    fn count(&mut self) -> u64 {
+        let mut count = 0;
+        let mut pl = [0; 16];
+        loop {
+            let len = self.next_batch(&mut pl);
+            if len == 0 {
+                break;
+            }
+            count += len as u64;
+        }
+        count
+    }
+
+    /// Ошибка, из-за которой декодер досрочно прекратил выдачу элементов
+    ///
+    /// Составные декодеры возвращают ошибку любого из вложенных списков.
+    fn error(&self) -> Option<&DecodeError> {
+        None
+    }
+
+    /// Аналог [`PostingListDecoder::to_vec`], возвращающий ошибку для поврежденных данных
+    fn try_to_vec(mut self) -> Result<Vec<u64>, DecodeError>
+    where
+        Self: Sized,
+    {
+        let mut result = vec![];
+        let mut pl = [0; 16];
+        loop {
+            let len = self.next_batch(&mut pl);
+            if len == 0 {
+                break;
+            }
+            result.extend(&pl[0..len]);
+        }
+        match self.error() {
+            Some(error) => Err(error.clone()),
+            None => Ok(result),
+        }
+    }
+
     fn to_vec(mut self) -> Vec<u64>
     where
         Self: Sized,
@@ -56,6 +122,22 @@ pub fn exclude(a: PostingList, b: PostingList) -> PostingList {
     Exclude(a, b).into()
 }
 
+pub fn complement(universe: PostingList, list: PostingList) -> PostingList {
+    Complement(universe, list).into()
+}
+
+pub fn merge_n(lists: Vec<PostingList>) -> PostingList {
+    MergeN::new(lists).into()
+}
+
+pub fn intersect_n(lists: Vec<PostingList>) -> PostingList {
+    IntersectN::new(lists).into()
+}
+
+pub fn threshold(k: usize, lists: Vec<PostingList>) -> PostingList {
+    Threshold::new(k, lists).into()
+}
+
 pub struct PostingList {
     decoder: Box<dyn PostingListDecoder>,
     buffer: [u64; 16],
@@ -104,6 +186,29 @@ impl PostingList {
         current
     }
 
+    /// Возвращает количество оставшихся элементов, исчерпывая список (см. [`PostingListDecoder::count`])
+    pub fn count(&mut self) -> u64 {
+        let buffered = self.len.saturating_sub(self.position) as u64;
+        self.len = 0;
+        self.position = 0;
+        buffered + self.decoder.count()
+    }
+
+    /// Ошибка, из-за которой список досрочно прекратил выдачу элементов (см. [`PostingListDecoder::error`])
+    pub fn error(&self) -> Option<&DecodeError> {
+        self.decoder.error()
+    }
+
+    /// Проверяет, что элементы списка не были потеряны из-за повреждения данных
+    ///
+    /// Должен вызываться после чтения, так как ошибка обнаруживается только при декодировании.
+    pub fn check(&self) -> Result<(), DecodeError> {
+        match self.error() {
+            Some(error) => Err(error.clone()),
+            None => Ok(()),
+        }
+    }
+
     #[inline]
     pub fn current(&mut self) -> u64 {
         if !self.ensure_buffer_has_data() {
@@ -126,6 +231,12 @@ impl PostingList {
 pub struct Merge(pub PostingList, pub PostingList);
 
 impl PostingListDecoder for Merge {
+    fn next_batch_advance(&mut self, target: u64, buffer: &mut PlBuffer) -> usize {
+        self.0.advance(target);
+        self.1.advance(target);
+        self.next_batch(buffer)
+    }
+
     fn next_batch(&mut self, buffer: &mut PlBuffer) -> usize {
         let mut a = self.0.current();
         let mut b = self.1.current();
@@ -150,6 +261,10 @@ impl PostingListDecoder for Merge {
         }
         i
     }
+
+    fn error(&self) -> Option<&DecodeError> {
+        self.0.error().or_else(|| self.1.error())
+    }
 }
 
 pub struct Intersect(pub PostingList, pub PostingList);
@@ -178,11 +293,20 @@ impl PostingListDecoder for Intersect {
         }
         i
     }
+
+    fn error(&self) -> Option<&DecodeError> {
+        self.0.error().or_else(|| self.1.error())
+    }
 }
 
 pub struct Exclude(pub PostingList, pub PostingList);
 
 impl PostingListDecoder for Exclude {
+    fn next_batch_advance(&mut self, target: u64, buffer: &mut PlBuffer) -> usize {
+        self.0.advance(target);
+        self.next_batch(buffer)
+    }
+
     fn next_batch(&mut self, buffer: &mut PlBuffer) -> usize {
         let mut a = self.0.current();
         let mut b = self.1.current();
@@ -203,9 +327,197 @@ impl PostingListDecoder for Exclude {
         }
         i
     }
+
+    fn error(&self) -> Option<&DecodeError> {
+        self.0.error().or_else(|| self.1.error())
+    }
+}
+
+/// Дополнение posting list'а до заданного универсального множества
+///
+/// Возвращает элементы первого списка (универсума), которых нет во втором. В отличии от [`Exclude`]
+/// поддерживает [`PostingListDecoder::next_batch_advance`] за счет сдвига универсума, поэтому
+/// дополнение можно эффективно пересекать с небольшими списками.
+pub struct Complement(pub PostingList, pub PostingList);
+
+impl PostingListDecoder for Complement {
+    fn next_batch_advance(&mut self, target: u64, buffer: &mut PlBuffer) -> usize {
+        self.0.advance(target);
+        self.next_batch(buffer)
+    }
+
+    fn next_batch(&mut self, buffer: &mut PlBuffer) -> usize {
+        let mut u = self.0.current();
+        let mut i = 0;
+        while i < buffer.len() && u != NO_DOC {
+            if self.1.advance(u) != u {
+                buffer[i] = u;
+                i += 1;
+            }
+            u = self.0.next();
+        }
+        i
+    }
+
+    fn error(&self) -> Option<&DecodeError> {
+        self.0.error().or_else(|| self.1.error())
+    }
+}
+
+/// Объединение произвольного количества posting list'ов
+///
+/// В отличии от цепочки [`Merge`] требует одного буфера и одной кучи на все списки. Является частным
+/// случаем [`Threshold`] с `k = 1`.
+pub struct MergeN(Threshold);
+
+impl MergeN {
+    pub fn new(lists: Vec<PostingList>) -> Self {
+        Self(Threshold::new(1, lists))
+    }
+}
+
+impl PostingListDecoder for MergeN {
+    fn next_batch_advance(&mut self, target: u64, buffer: &mut PlBuffer) -> usize {
+        self.0.next_batch_advance(target, buffer)
+    }
+
+    fn next_batch(&mut self, buffer: &mut PlBuffer) -> usize {
+        self.0.next_batch(buffer)
+    }
+
+    fn error(&self) -> Option<&DecodeError> {
+        self.0.error()
+    }
 }
 
-#[derive(Debug)]
+/// Пересечение произвольного количества posting list'ов (leapfrog)
+///
+/// Кандидаты берутся из первого списка, остальные списки сдвигаются к кандидату через
+/// [`PostingList::advance`]. Если какой-либо список перескакивает кандидата, первый список сдвигается
+/// к новому значению. Поэтому списки следует передавать в порядке возрастания их размера.
+pub struct IntersectN(Vec<PostingList>);
+
+impl IntersectN {
+    pub fn new(lists: Vec<PostingList>) -> Self {
+        assert!(!lists.is_empty(), "At least one posting list expected");
+        Self(lists)
+    }
+
+    /// Возвращает первый элемент, не меньший текущего элемента первого списка, входящий во все списки
+    ///
+    /// Как только один из списков исчерпан, возвращает [`NO_DOC`] не сдвигая остальные списки до конца.
+    fn align(&mut self) -> u64 {
+        let mut candidate = self.0[0].current();
+        'leapfrog: while candidate != NO_DOC {
+            for list in self.0[1..].iter_mut() {
+                let value = list.advance(candidate);
+                if value == NO_DOC {
+                    return NO_DOC;
+                }
+                if value != candidate {
+                    candidate = self.0[0].advance(value);
+                    continue 'leapfrog;
+                }
+            }
+            break;
+        }
+        candidate
+    }
+}
+
+impl PostingListDecoder for IntersectN {
+    fn next_batch_advance(&mut self, target: u64, buffer: &mut PlBuffer) -> usize {
+        self.0[0].advance(target);
+        self.next_batch(buffer)
+    }
+
+    fn next_batch(&mut self, buffer: &mut PlBuffer) -> usize {
+        let mut i = 0;
+        while i < buffer.len() {
+            let value = self.align();
+            if value == NO_DOC {
+                break;
+            }
+            buffer[i] = value;
+            i += 1;
+            self.0[0].next();
+        }
+        i
+    }
+
+    fn error(&self) -> Option<&DecodeError> {
+        self.0.iter().find_map(PostingList::error)
+    }
+}
+
+/// Элементы, входящие как минимум в `k` из переданных posting list'ов
+///
+/// Текущие элементы всех списков хранятся в куче, поэтому стоимость получения очередного элемента –
+/// `O(log n)` на каждый список, в котором он встречается.
+pub struct Threshold {
+    k: usize,
+    lists: Vec<PostingList>,
+    heap: BinaryHeap<Reverse<(u64, usize)>>,
+}
+
+impl Threshold {
+    pub fn new(k: usize, mut lists: Vec<PostingList>) -> Self {
+        let mut heap = BinaryHeap::with_capacity(lists.len());
+        for (i, list) in lists.iter_mut().enumerate() {
+            let current = list.current();
+            if current != NO_DOC {
+                heap.push(Reverse((current, i)));
+            }
+        }
+        Self { k, lists, heap }
+    }
+}
+
+impl PostingListDecoder for Threshold {
+    fn next_batch_advance(&mut self, target: u64, buffer: &mut PlBuffer) -> usize {
+        while let Some(Reverse((value, i))) = self.heap.peek().cloned() {
+            if value >= target {
+                break;
+            }
+            self.heap.pop();
+            let next = self.lists[i].advance(target);
+            if next != NO_DOC {
+                self.heap.push(Reverse((next, i)));
+            }
+        }
+        self.next_batch(buffer)
+    }
+
+    fn next_batch(&mut self, buffer: &mut PlBuffer) -> usize {
+        let mut i = 0;
+        while i < buffer.len() && self.heap.len() >= self.k.max(1) {
+            let Reverse((value, _)) = *self.heap.peek().unwrap();
+            let mut count = 0;
+            while let Some(Reverse((v, list))) = self.heap.peek().cloned() {
+                if v != value {
+                    break;
+                }
+                self.heap.pop();
+                count += 1;
+                let next = self.lists[list].next();
+                if next != NO_DOC {
+                    self.heap.push(Reverse((next, list)));
+                }
+            }
+            if count >= self.k {
+                buffer[i] = value;
+                i += 1;
+            }
+        }
+        i
+    }
+
+    fn error(&self) -> Option<&DecodeError> {
+        self.lists.iter().find_map(PostingList::error)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct VecPostingList {
     data: Vec<u64>,
     pos: usize,
@@ -238,6 +550,12 @@ impl PostingListDecoder for VecPostingList {
         self.pos += len;
         len
     }
+
+    fn count(&mut self) -> u64 {
+        let count = self.data.len().saturating_sub(self.pos);
+        self.pos = self.data.len();
+        count as u64
+    }
 }
 
 #[derive(Clone)]
@@ -279,6 +597,12 @@ impl PostingListDecoder for RangePostingList {
         self.next += len as u64;
         len
     }
+
+    fn count(&mut self) -> u64 {
+        let count = self.range.end.saturating_sub(self.next);
+        self.next = self.next.max(self.range.end);
+        count
+    }
 }
 
 #[cfg(test)]
@@ -318,6 +642,39 @@ mod tests {
         assert_eq!(values, vec![1, 4, 5]);
     }
 
+    #[test]
+    fn check_count() {
+        let mut list = PostingList::from(RangePostingList::new(1..1000));
+        assert_eq!(list.next(), 1);
+        assert_eq!(list.advance(100), 100);
+        assert_eq!(list.count(), 900);
+        assert_eq!(list.current(), NO_DOC);
+
+        let a = VecPostingList::new(&[1, 3, 5, 7]);
+        let b = RangePostingList::new(2..7);
+        assert_eq!(Merge(a.into(), b.into()).count(), 7);
+    }
+
+    #[test]
+    fn check_complement() {
+        let universe = RangePostingList::new(1..10);
+        let list = VecPostingList::new(&[2, 3, 7, 12]);
+
+        let values = Complement(universe.into(), list.into()).to_vec();
+        assert_eq!(values, vec![1, 4, 5, 6, 8, 9]);
+    }
+
+    #[test]
+    fn check_complement_advance() {
+        let universe = RangePostingList::new(1..1_000_000);
+        let list = RangePostingList::new(1..500_000);
+
+        let mut complement = PostingList::from(Complement(universe.into(), list.into()));
+        assert_eq!(complement.advance(10), 500_000);
+        assert_eq!(complement.advance(999_999), 999_999);
+        assert_eq!(complement.next(), NO_DOC);
+    }
+
     #[test]
     fn check_no_exclude() {
         let a = RangePostingList::new(1..1_000);
@@ -372,6 +729,146 @@ mod tests {
         });
     }
 
+    #[test]
+    fn check_complement_massive() {
+        run_seeded_test::<StdRng>(None, |mut rng| {
+            for _ in 0..100 {
+                let a = random_posting_list(&mut rng);
+                let b = random_posting_list(&mut rng);
+
+                let expected = naive_exclude(&a.data, &b.data);
+                let actual = Complement(a.into(), b.into()).to_vec();
+
+                assert_eq!(actual, expected);
+            }
+        });
+    }
+
+    #[test]
+    fn check_threshold() {
+        let a = VecPostingList::new(&[1, 2, 3, 5]);
+        let b = VecPostingList::new(&[2, 3, 4]);
+        let c = VecPostingList::new(&[3, 4, 5, 6]);
+        let lists = || vec![a.clone().into(), b.clone().into(), c.clone().into()];
+
+        assert_eq!(Threshold::new(1, lists()).to_vec(), vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(Threshold::new(2, lists()).to_vec(), vec![2, 3, 4, 5]);
+        assert_eq!(Threshold::new(3, lists()).to_vec(), vec![3]);
+        assert_eq!(Threshold::new(4, lists()).to_vec(), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn check_threshold_massive() {
+        run_seeded_test::<StdRng>(None, |mut rng| {
+            for _ in 0..100 {
+                let lists = (0..rng.gen_range(1..8))
+                    .map(|_| random_posting_list(&mut rng))
+                    .collect::<Vec<_>>();
+                let k = rng.gen_range(1..=lists.len());
+
+                let expected = naive_threshold(k, &lists);
+                let actual = Threshold::new(k, lists.into_iter().map(Into::into).collect());
+                let mut actual = PostingList::from(actual);
+
+                // чередуем next() и advance()
+                let mut target = 0;
+                for expected in expected {
+                    let value = if rng.gen_bool(0.5) {
+                        target = rng.gen_range(target..=expected);
+                        actual.advance(target)
+                    } else {
+                        actual.next()
+                    };
+                    assert_eq!(value, expected);
+                    target = expected + 1;
+                }
+                assert_eq!(actual.advance(target), NO_DOC);
+            }
+        });
+    }
+
+    #[test]
+    fn check_merge_exclude_advance_massive() {
+        run_seeded_test::<StdRng>(None, |mut rng| {
+            for _ in 0..100 {
+                let a = random_posting_list(&mut rng);
+                let b = random_posting_list(&mut rng);
+
+                let cases = [
+                    (
+                        naive_merge(&a.data, &b.data),
+                        merge(a.clone().into(), b.clone().into()),
+                    ),
+                    (naive_exclude(&a.data, &b.data), exclude(a.into(), b.into())),
+                ];
+                for (expected, mut actual) in cases {
+                    // чередуем next() и advance()
+                    let mut target = 0;
+                    for expected in expected {
+                        let value = if rng.gen_bool(0.5) {
+                            target = rng.gen_range(target..=expected);
+                            actual.advance(target)
+                        } else {
+                            actual.next()
+                        };
+                        assert_eq!(value, expected);
+                        target = expected + 1;
+                    }
+                    assert_eq!(actual.advance(target), NO_DOC);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn check_merge_n_massive() {
+        run_seeded_test::<StdRng>(None, |mut rng| {
+            for _ in 0..100 {
+                let lists = (0..rng.gen_range(1..8))
+                    .map(|_| random_posting_list(&mut rng))
+                    .collect::<Vec<_>>();
+
+                let expected = lists.iter().fold(vec![], |r, l| naive_merge(&r, &l.data));
+                let actual = MergeN::new(lists.into_iter().map(Into::into).collect()).to_vec();
+
+                assert_eq!(actual, expected);
+            }
+        });
+    }
+
+    #[test]
+    fn check_intersect_n_stops_on_exhausted_list() {
+        let mut intersect = IntersectN::new(vec![
+            RangePostingList::new(1..1_000_000).into(),
+            RangePostingList::new(5..7).into(),
+        ]);
+        let mut buffer = [0; 16];
+        assert_eq!(intersect.next_batch(&mut buffer), 2);
+        assert_eq!(&buffer[..2], &[5, 6]);
+        assert_eq!(intersect.next_batch(&mut buffer), 0);
+
+        // первый список не дочитывается до конца после исчерпания второго
+        assert_eq!(intersect.0[0].current(), 7);
+    }
+
+    #[test]
+    fn check_intersect_n_massive() {
+        run_seeded_test::<StdRng>(None, |mut rng| {
+            for _ in 0..100 {
+                let lists = (0..rng.gen_range(1..5))
+                    .map(|_| random_dense_posting_list(&mut rng))
+                    .collect::<Vec<_>>();
+
+                let expected = lists[1..]
+                    .iter()
+                    .fold(lists[0].data.clone(), |r, l| naive_intersect(&r, &l.data));
+                let actual = IntersectN::new(lists.into_iter().map(Into::into).collect()).to_vec();
+
+                assert_eq!(actual, expected);
+            }
+        });
+    }
+
     #[test]
     fn range_posting_list_next_advance() {
         let mut t = RangePostingList::new(1..1000);
@@ -398,6 +895,21 @@ mod tests {
         union
     }
 
+    fn naive_threshold(k: usize, lists: &[VecPostingList]) -> Vec<u64> {
+        let mut all = lists
+            .iter()
+            .flat_map(|l| l.data.iter().cloned())
+            .collect::<Vec<_>>();
+        all.sort();
+        let mut result = vec![];
+        for chunk in all.chunk_by(|a, b| a == b) {
+            if chunk.len() >= k {
+                result.push(chunk[0]);
+            }
+        }
+        result
+    }
+
     fn naive_intersect(a: &[u64], b: &[u64]) -> Vec<u64> {
         a.iter()
             .filter(|i| b.binary_search(i).is_ok())
@@ -434,6 +946,18 @@ mod tests {
         }
     }
 
+    fn random_dense_posting_list(rng: &mut impl Rng) -> VecPostingList {
+        let size: usize = rng.gen_range(1..200);
+        let mut list = Vec::with_capacity(size);
+
+        let mut doc_id = 0;
+        for _ in 0..size {
+            doc_id += rng.gen_range(1..4);
+            list.push(doc_id)
+        }
+        VecPostingList::new(&list)
+    }
+
     fn random_posting_list(rng: &mut impl Rng) -> VecPostingList {
         let size: usize = rng.gen_range(1..20);
         let mut list = Vec::with_capacity(size);
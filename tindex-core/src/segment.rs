@@ -0,0 +1,162 @@
+//! Отображенные в память индексные файлы
+//!
+//! Чтение индекса через [`Segment`] не требует системных вызовов на каждый запрос: файл отображается в память
+//! один раз, а декодеры читают данные непосредственно из отображения. [`SegmentCache`] позволяет разделять
+//! сегменты между параллельными запросами и переоткрывает файл, если индексатор его заменил.
+use crate::prelude::*;
+use memmap2::Mmap;
+use std::{
+    collections::HashMap,
+    fs::{File, Metadata},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+/// Индексный файл, отображенный в память
+///
+/// Клонирование сегмента дешевое – все копии разделяют одно отображение, которое освобождается
+/// вместе с последней копией. Файл не должен изменяться на месте пока он отображен в память, поэтому
+/// индексатор всегда заменяет файлы целиком.
+#[derive(Clone)]
+pub struct Segment(Arc<Mmap>);
+
+impl Segment {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::map(&File::open(path.as_ref())?)
+    }
+
+    fn map(file: &File) -> Result<Self> {
+        // SAFETY: индексные файлы не изменяются на месте, а заменяются атомарно
+        let mmap = unsafe { Mmap::map(file)? };
+        Ok(Self(Arc::new(mmap)))
+    }
+}
+
+impl AsRef<[u8]> for Segment {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Разделяемый между потоками кеш сегментов
+///
+/// При каждом обращении проверяются метаданные файла. Если файл был заменен, он отображается в память заново,
+/// при этом уже выданные копии старого сегмента остаются валидными до тех пор пока используются.
+///
+/// Каждое поколение терма записывается в новый файл, поэтому при отображении нового файла из кеша удаляются
+/// сегменты, файлы которых больше не существуют. Иначе кеш удерживал бы отображения (и место на диске)
+/// всех когда-либо открытых поколений.
+#[derive(Default)]
+pub struct SegmentCache {
+    segments: RwLock<HashMap<PathBuf, (FileId, Segment)>>,
+}
+
+impl SegmentCache {
+    pub fn get(&self, path: &Path) -> Result<Segment> {
+        let id = FileId::from(&path.metadata()?);
+        if let Some((cached_id, segment)) = self.segments.read().unwrap().get(path) {
+            if *cached_id == id {
+                return Ok(segment.clone());
+            }
+        }
+
+        let file = File::open(path)?;
+        let id = FileId::from(&file.metadata()?);
+        let segment = Segment::map(&file)?;
+        let mut segments = self.segments.write().unwrap();
+        segments.retain(|path, _| path.exists());
+        segments.insert(path.to_path_buf(), (id, segment.clone()));
+        Ok(segment)
+    }
+
+    /// Количество сегментов в кеше
+    pub fn len(&self) -> usize {
+        self.segments.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Идентификатор конкретной версии файла
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct FileId {
+    dev: u64,
+    ino: u64,
+    len: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+impl From<&Metadata> for FileId {
+    fn from(m: &Metadata) -> Self {
+        Self {
+            dev: m.dev(),
+            ino: m.ino(),
+            len: m.len(),
+            mtime: m.mtime(),
+            mtime_nsec: m.mtime_nsec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::{block::BlockEncoder, Encoder, FileDecoder};
+    use crate::PostingListDecoder;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn check_segment_reopened_after_replace() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("term.idx");
+        write(&path, 1..10)?;
+
+        let cache = SegmentCache::default();
+        let old = cache.get(&path)?;
+        assert_eq!(
+            FileDecoder::new(cache.get(&path)?)?.to_vec(),
+            (1..10).collect::<Vec<_>>()
+        );
+
+        let tmp = dir.path().join("term.idx.tmp");
+        write(&tmp, 20..25)?;
+        fs::rename(&tmp, &path)?;
+
+        assert_eq!(
+            FileDecoder::new(cache.get(&path)?)?.to_vec(),
+            (20..25).collect::<Vec<_>>()
+        );
+        // ранее выданный сегмент продолжает указывать на старую версию файла
+        assert_eq!(FileDecoder::new(old)?.to_vec(), (1..10).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn check_removed_segments_evicted() -> Result<()> {
+        let dir = tempdir()?;
+        let cache = SegmentCache::default();
+        for generation in 1..10 {
+            let path = dir.path().join(format!("term.{}.idx", generation));
+            write(&path, 1..generation + 2)?;
+            let previous = dir.path().join(format!("term.{}.idx", generation - 1));
+            if previous.exists() {
+                fs::remove_file(previous)?;
+            }
+            assert_eq!(FileDecoder::new(cache.get(&path)?)?.count(), generation + 1);
+            assert_eq!(cache.len(), 1);
+        }
+        Ok(())
+    }
+
+    fn write(path: &Path, values: impl Iterator<Item = u64>) -> Result<()> {
+        let mut encoder = BlockEncoder::create(path)?;
+        encoder.write_values(values)?;
+        encoder.finish()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,212 @@
+//! Внешняя сортировка идентификаторов
+//!
+//! Источники данных возвращают идентификаторы в произвольном порядке, а posting list должен быть
+//! отсортирован. [`ExternalSorter`] накапливает в памяти не более заданного количества значений. Заполненный
+//! буфер сортируется и сбрасывается на диск в виде отсортированного прогона (run). После этого все прогоны
+//! сливаются k-путевым слиянием прямо в [`Encoder`]. Таким образом объем используемой памяти не зависит от
+//! размера результата запроса.
+//!
+//! Дубликаты удаляются, так что в [`Encoder`] попадает строго возрастающая последовательность.
+use crate::{encoding::Encoder, prelude::*};
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+/// Количество значений, которое по умолчанию сортируется в памяти (64 МБ)
+pub const DEFAULT_CAPACITY: usize = 8 * 1024 * 1024;
+
+/// Начальный размер буфера. Буфер растет по мере необходимости, поэтому небольшие результаты запросов не
+/// требуют выделения памяти на `capacity` значений
+const INITIAL_CAPACITY: usize = 1024;
+
+pub struct ExternalSorter {
+    dir: PathBuf,
+    capacity: usize,
+    buffer: Vec<u64>,
+    runs: Vec<File>,
+}
+
+impl ExternalSorter {
+    /// Создает сортировщик, который хранит прогоны во временных файлах директории `dir`
+    ///
+    /// `capacity` – максимальное количество значений, которое удерживается в памяти.
+    pub fn new_in(dir: impl Into<PathBuf>, capacity: usize) -> Self {
+        assert!(capacity > 0, "Capacity should be positive");
+        Self {
+            dir: dir.into(),
+            capacity,
+            buffer: Vec::with_capacity(capacity.min(INITIAL_CAPACITY)),
+            runs: vec![],
+        }
+    }
+
+    pub fn push(&mut self, value: u64) -> IoResult<()> {
+        let len = self.buffer.len();
+        if len == self.buffer.capacity() {
+            // буфер растет вдвое, но не больше чем до `capacity` значений
+            self.buffer
+                .reserve_exact(len.min(self.capacity - len).max(1));
+        }
+        self.buffer.push(value);
+        if self.buffer.len() == self.capacity {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Количество прогонов, сброшенных на диск
+    pub fn runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// Сортирует буфер и записывает его на диск в виде прогона
+    ///
+    /// Прогоны хранятся в анонимных временных файлах, которые удаляются операционной системой при
+    /// закрытии, в том числе если индексатор завершился аварийно.
+    fn spill(&mut self) -> IoResult<()> {
+        self.buffer.sort_unstable();
+        self.buffer.dedup();
+        let mut file = tempfile::tempfile_in(&self.dir)?;
+        let mut writer = BufWriter::new(&mut file);
+        for value in self.buffer.drain(..) {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        writer.flush()?;
+        drop(writer);
+        file.seek(SeekFrom::Start(0))?;
+        self.runs.push(file);
+        Ok(())
+    }
+
+    /// Записывает уникальные значения в `encoder` в порядке возрастания и возвращает их количество
+    ///
+    /// [`Encoder::finish`] не вызывается.
+    pub fn write_to(mut self, encoder: &mut impl Encoder) -> IoResult<u64> {
+        if self.runs.is_empty() {
+            self.buffer.sort_unstable();
+            self.buffer.dedup();
+            encoder.write_values(self.buffer.iter().copied())?;
+            return Ok(self.buffer.len() as u64);
+        }
+        if !self.buffer.is_empty() {
+            self.spill()?;
+        }
+
+        let mut runs = self.runs.into_iter().map(Run::new).collect::<Vec<_>>();
+        let mut heap = BinaryHeap::with_capacity(runs.len());
+        for (i, run) in runs.iter_mut().enumerate() {
+            if let Some(value) = run.next()? {
+                heap.push(Reverse((value, i)));
+            }
+        }
+
+        let mut count = 0;
+        let mut last = None;
+        while let Some(Reverse((value, i))) = heap.pop() {
+            // одно и то же значение может встречаться в нескольких прогонах
+            if last != Some(value) {
+                encoder.write(value)?;
+                last = Some(value);
+                count += 1;
+            }
+            if let Some(value) = runs[i].next()? {
+                heap.push(Reverse((value, i)));
+            }
+        }
+        Ok(count)
+    }
+}
+
+/// Отсортированный прогон на диске
+struct Run(BufReader<File>);
+
+impl Run {
+    fn new(file: File) -> Self {
+        Self(BufReader::new(file))
+    }
+
+    fn next(&mut self) -> IoResult<Option<u64>> {
+        let mut bytes = [0; 8];
+        match self.0.read_exact(&mut bytes) {
+            Ok(_) => Ok(Some(u64::from_le_bytes(bytes))),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+    use tempfile::tempdir;
+
+    struct VecEncoder(Vec<u64>);
+
+    impl Encoder for VecEncoder {
+        fn write(&mut self, value: u64) -> IoResult<()> {
+            self.0.push(value);
+            Ok(())
+        }
+    }
+
+    fn sort(values: &[u64], capacity: usize) -> Result<(Vec<u64>, usize)> {
+        let dir = tempdir()?;
+        let mut sorter = ExternalSorter::new_in(dir.path(), capacity);
+        for value in values {
+            sorter.push(*value)?;
+        }
+        let runs = sorter.runs();
+        let mut encoder = VecEncoder(vec![]);
+        let count = sorter.write_to(&mut encoder)?;
+        assert_eq!(count, encoder.0.len() as u64);
+        Ok((encoder.0, runs))
+    }
+
+    #[test]
+    fn check_external_sort() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(1);
+        let values = (0..10_000)
+            .map(|_| rng.gen_range(1..1_000_000))
+            .collect::<Vec<_>>();
+        let mut expected = values.clone();
+        expected.sort_unstable();
+        expected.dedup();
+        assert!(expected.len() < values.len());
+
+        let (sorted, runs) = sort(&values, 1000)?;
+        assert_eq!(runs, 10);
+        assert_eq!(sorted, expected);
+
+        let (sorted, runs) = sort(&values, 3333)?;
+        assert_eq!(runs, 3);
+        assert_eq!(sorted, expected);
+
+        let (sorted, runs) = sort(&values, values.len() + 1)?;
+        assert_eq!(runs, 0);
+        assert_eq!(sorted, expected);
+
+        assert_eq!(sort(&[], 10)?, (vec![], 0));
+        Ok(())
+    }
+
+    #[test]
+    fn check_buffer_growth() -> Result<()> {
+        let dir = tempdir()?;
+        let sorter = ExternalSorter::new_in(dir.path(), DEFAULT_CAPACITY);
+        assert_eq!(sorter.buffer.capacity(), INITIAL_CAPACITY);
+
+        let mut sorter = ExternalSorter::new_in(dir.path(), 3000);
+        for value in 0..2999 {
+            sorter.push(value)?;
+        }
+        assert_eq!(sorter.buffer.capacity(), 3000);
+        sorter.push(2999)?;
+        assert_eq!(sorter.runs(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,34 @@
+use crate::{cli::read_views, prelude::*, query::parse_query, DirectoryIndex, Universe};
+use clap::Parser;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[clap(about = "Counting documents matching query without listing them")]
+pub struct Opts {
+    /// path to an index
+    path: PathBuf,
+
+    /// universe for complement operator: term name or id range (eg. "1..1000000")
+    #[clap(long)]
+    universe: Option<Universe>,
+
+    /// config with named queries (views)
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// query to run (eg. "crit1 & crit2")
+    query: String,
+}
+
+pub async fn main(opts: Opts) -> Result<()> {
+    let index = DirectoryIndex::new(opts.path)
+        .with_universe(opts.universe)
+        .with_views(read_views(opts.config.as_deref())?);
+    let snapshot = index.snapshot()?;
+
+    let mut list = parse_query(&opts.query, &snapshot)?;
+    let count = list.count();
+    list.check()?;
+    println!("{}", count);
+    Ok(())
+}
@@ -1,18 +1,33 @@
 use crate::{
-    config::{Config, Connection, Database, Query},
+    config::{Config, Connection, Database, DeltaQuery, Format, Query},
+    manifest::{
+        self, delta_paths, generation_paths, sql_hash, term_path, Delta, Manifest, Term,
+        GC_GRACE_PERIOD, MANIFEST_FILE,
+    },
     prelude::*,
+    DirectoryIndex, Index,
 };
+use anyhow::bail;
 use chrono::{DateTime, Utc};
 use clap::Parser;
 use fn_error_context::context;
 use std::{
-    collections::{BinaryHeap, HashSet, LinkedList},
-    fs::File,
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet, LinkedList},
+    fs::{self, File, OpenOptions},
+    io::ErrorKind,
+    iter,
     path::{Path, PathBuf},
     thread::{self, sleep, JoinHandle},
-    time::Duration,
+    time::{Duration, SystemTime},
 };
-use tindex_core::encoding::{Encoder, PlainTextEncoder};
+use tempfile::NamedTempFile;
+use tindex_core::{
+    encoding::{block::BlockEncoder, roaring::RoaringEncoder, Encoder, FileDecoder},
+    sort::{self, ExternalSorter},
+    Intersect, PostingList, PostingListDecoder, NO_DOC,
+};
+
+mod derived;
 
 #[derive(Parser, Debug)]
 #[clap(about = "Run indexation for all queries in a config")]
@@ -26,11 +41,19 @@ pub struct IndexOpts {
 
 /// Запускает цикл обновления всех запросов в соответствии с расписанием
 pub fn do_index(opts: IndexOpts) -> Result<()> {
-    let config = read_config(&opts.config)?;
+    let config = Config::read(&opts.config)?;
 
     let mut handles = LinkedList::new();
     handles.extend(start_workers(config.mysql, &opts));
     handles.extend(start_workers(config.clickhouse, &opts));
+    handles.extend(start_workers(config.postgres, &opts));
+    handles.extend(start_workers(config.sqlite, &opts));
+    handles.extend(start_workers(config.files, &opts));
+    if !config.derived.is_empty() {
+        let path = opts.path.clone();
+        let (derived, views) = (config.derived, config.views);
+        handles.push_back(thread::spawn(move || derived::worker(derived, views, path)));
+    }
     wait_for_all_workers(handles)
 }
 
@@ -89,7 +112,7 @@ pub struct UpdateOpts {
 
 /// Обновляет указанные запросы по имени
 pub fn do_update(opts: UpdateOpts) -> Result<()> {
-    let config = read_config(&opts.config)?;
+    let config = Config::read(&opts.config)?;
 
     let mut query_names = HashSet::new();
     query_names.extend(opts.queries);
@@ -102,6 +125,25 @@ pub fn do_update(opts: UpdateOpts) -> Result<()> {
         run_queries(clickhouse, &query_names, &opts.path)?;
     }
 
+    for postgres in &config.postgres.unwrap_or_default() {
+        run_queries(postgres, &query_names, &opts.path)?;
+    }
+
+    for sqlite in &config.sqlite.unwrap_or_default() {
+        run_queries(sqlite, &query_names, &opts.path)?;
+    }
+
+    for files in &config.files.unwrap_or_default() {
+        run_queries(files, &query_names, &opts.path)?;
+    }
+
+    let derived = config
+        .derived
+        .into_iter()
+        .filter(|d| query_names.contains(&d.name))
+        .collect::<Vec<_>>();
+    derived::update(derived, config.views, &opts.path)?;
+
     Ok(())
 }
 
@@ -127,12 +169,24 @@ fn db_worker<DB: Database>(d: DB, path: PathBuf) -> Result<()> {
         schedule_next(q.to_owned(), &mut heap)
     }
 
+    let mut watcher = FileWatcher::new(d.list_queries());
+
     let mut conn = d.connect()?;
-    // Извлекаем самый ближайший запланированный запрос
-    while let Some(ScheduledQuery(time, q)) = heap.pop() {
-        sleep_until(time);
-        run_query(&mut conn, &q, &path)?;
-        schedule_next(q, &mut heap);
+    loop {
+        for q in watcher.changed(d.list_queries()) {
+            info!("Query {} file changed", q.name());
+            run_query(&mut conn, q, &path)?;
+        }
+        // Извлекаем самый ближайший запланированный запрос
+        match heap.peek() {
+            Some(ScheduledQuery(time, _)) if *time <= Utc::now() => {
+                let ScheduledQuery(_, q) = heap.pop().unwrap();
+                run_query(&mut conn, &q, &path)?;
+                schedule_next(q, &mut heap);
+            }
+            None if watcher.is_empty() => break,
+            _ => sleep(Duration::from_secs(1)),
+        }
     }
 
     Ok(())
@@ -148,27 +202,272 @@ fn schedule_next<Q: Query>(q: Q, heap: &mut BinaryHeap<ScheduledQuery<Q>>) {
 #[context("Processing query {} on database {}", query.name(), db.name())]
 fn run_query<C: Connection>(db: &mut C, query: &C::Query, path: &Path) -> Result<()> {
     info!("Query run (name: {}, db: {})", db.name(), query.name());
-    let path = path.join(query.name()).with_extension("idx");
 
-    let mut ids = db.execute(query)?;
-    let size = ids.len();
-    ids.sort_unstable();
-    let file = File::create(path)?;
-    write(ids, PlainTextEncoder(file))?;
+    let Some(delta) = query.delta() else {
+        return run_full(db, query, path, None);
+    };
+    let manifest = Manifest::load(path)?;
+    match manifest.terms.get(query.name()) {
+        // дельту можно применить только к терму, построенному тем же запросом
+        Some(term) if term.sql_hash == sql_hash(query.sql()) && term.format == query.format() => {
+            match term.watermark {
+                Some(watermark) => run_delta(db, query, delta, path, term, watermark),
+                None => run_full(db, query, path, Some(delta)),
+            }
+        }
+        _ => run_full(db, query, path, Some(delta)),
+    }
+}
+
+/// Полностью перестраивает терм по результату запроса
+fn run_full<C: Connection>(
+    db: &mut C,
+    query: &C::Query,
+    path: &Path,
+    delta: Option<&DeltaQuery>,
+) -> Result<()> {
+    // изменения, сделанные во время выполнения запроса, будут повторно получены следующей дельтой
+    let watermark = delta
+        .map(|delta| read_watermark(db, &delta.watermark))
+        .transpose()?;
+    let result = execute_sorted(path, query.name(), query.format(), |sink| {
+        db.execute(query, sink)
+    })?;
+    let term = Term {
+        generation: 0,
+        records: result.records,
+        database: db.name().to_string(),
+        sql_hash: sql_hash(query.sql()),
+        built_at: Utc::now(),
+        format: query.format(),
+        format_version: query.format().version(),
+        inputs: BTreeMap::new(),
+        watermark,
+        deltas: vec![],
+    };
+    publish_term(path, query.name(), result.file, term, GC_GRACE_PERIOD)?;
     info!(
-        "Query finished (name: {}, records: {})...",
+        "Query finished (name: {}, records: {}, duplicates: {})...",
         query.name(),
-        size
+        result.records,
+        result.duplicates
     );
     Ok(())
 }
 
-fn sleep_until(time: DateTime<Utc>) {
-    while Utc::now() < time {
-        sleep(Duration::from_secs(1));
+/// Записывает изменения терма с момента `since` в виде дельты
+///
+/// Изменения запрашиваются в окне `(since, watermark]`, где `watermark` – текущее значение, прочитанное
+/// до выполнения запросов дельты.
+/// Если количество дельт терма достигло [`DeltaQuery::compact_after`], терм перестраивается в один файл.
+fn run_delta<C: Connection>(
+    db: &mut C,
+    query: &C::Query,
+    delta: &DeltaQuery,
+    path: &Path,
+    term: &Term,
+    since: u64,
+) -> Result<()> {
+    let watermark = read_watermark(db, &delta.watermark)?;
+    if watermark <= since {
+        info!("Query {} has no changes since {}", query.name(), since);
+        return Ok(());
+    }
+    let (name, format) = (query.name(), query.format());
+    let additions = execute_sorted(path, name, format, |sink| {
+        db.execute_sql(&DeltaQuery::bind(&delta.additions, since, watermark), sink)
+    })?;
+    let removals = execute_sorted(path, name, format, |sink| {
+        db.execute_sql(&DeltaQuery::bind(&delta.removals, since, watermark), sink)
+    })?;
+    // порядок изменений внутри окна неизвестен, поэтому состояние такого идентификатора не определено
+    let conflicts = Intersect(
+        FileDecoder::open(additions.file.path())?.into(),
+        FileDecoder::open(removals.file.path())?.into(),
+    )
+    .try_to_vec()?;
+    if let Some(id) = conflicts.first() {
+        bail!(
+            "{} identifiers (e.g. {}) are returned by both additions and removals queries",
+            conflicts.len(),
+            id
+        );
+    }
+    info!(
+        "Query delta finished (name: {}, additions: {}, removals: {}, watermark: {})",
+        name, additions.records, removals.records, watermark
+    );
+    let published = Delta {
+        generation: 0,
+        additions: additions.records,
+        removals: removals.records,
+        watermark,
+        built_at: Utc::now(),
+    };
+    let files = (additions.file, removals.file);
+    if !publish_delta(
+        path,
+        name,
+        term.version(),
+        files,
+        published,
+        GC_GRACE_PERIOD,
+    )? {
+        warn!("Term {} was updated concurrently, delta discarded", name);
+        return Ok(());
+    }
+    if term.deltas.len() + 1 >= delta.compact_after {
+        compact(path, name, format)?;
+    }
+    Ok(())
+}
+
+/// Текущее значение watermark'а источника: максимальное из значений, возвращенных запросом
+fn read_watermark(db: &mut impl Connection, sql: &str) -> Result<u64> {
+    let mut watermark = None;
+    db.execute_sql(sql, &mut |value| {
+        watermark = watermark.max(Some(value));
+        Ok(())
+    })?;
+    watermark.context("Watermark query returned no rows")
+}
+
+/// Отсортированный результат запроса, записанный во временный файл
+struct SortedResult {
+    file: NamedTempFile,
+    records: u64,
+    duplicates: u64,
+}
+
+/// Выполняет запрос функцией `execute` и записывает уникальные идентификаторы во временный файл
+fn execute_sorted(
+    path: &Path,
+    name: &str,
+    format: Format,
+    execute: impl FnOnce(&mut dyn FnMut(u64) -> Result<()>) -> Result<()>,
+) -> Result<SortedResult> {
+    // результат запроса может не поместиться в память, поэтому сортируем его с вытеснением на диск
+    let mut sorter = ExternalSorter::new_in(path, sort::DEFAULT_CAPACITY);
+    let (mut rows, mut invalid) = (0, 0);
+    execute(&mut |id| {
+        rows += 1;
+        // такие идентификаторы не могут быть записаны в posting list
+        if id == 0 || id == NO_DOC {
+            invalid += 1;
+            return Ok(());
+        }
+        Ok(sorter.push(id)?)
+    })?;
+    if invalid > 0 {
+        warn!(
+            "Query {} returned {} invalid identifiers (non-positive or {}), skipped",
+            name, invalid, NO_DOC
+        );
+    }
+    let mut records = 0;
+    let file = write_temp(path, |file| {
+        records = match format {
+            Format::Block => write_sorted(sorter, BlockEncoder::new(file)?)?,
+            Format::Roaring => write_sorted(sorter, RoaringEncoder::new(file)?)?,
+        };
+        Ok(())
+    })?;
+    Ok(SortedResult {
+        file,
+        records,
+        duplicates: rows - invalid - records,
+    })
+}
+
+/// Перестраивает терм вместе с его дельтами в один файл
+///
+/// Запросы к терму во время компактификации продолжают читать дельты. Если за это время терм был
+/// изменен, результат компактификации отбрасывается.
+#[context("Compacting term {}", name)]
+fn compact(dir: &Path, name: &str, format: Format) -> Result<()> {
+    let index = DirectoryIndex::new(dir.to_path_buf());
+    let snapshot = index.snapshot()?;
+    let Some(term) = snapshot.manifest().terms.get(name) else {
+        return Ok(());
+    };
+    let mut list = PostingList::from(snapshot.lookup(name)?);
+    let mut records = 0u64;
+    let file = write_temp(dir, |file| {
+        let ids = iter::from_fn(|| Some(list.next()).filter(|id| *id != NO_DOC))
+            .inspect(|_| records += 1);
+        match format {
+            Format::Block => write(ids, BlockEncoder::new(file)?),
+            Format::Roaring => write(ids, RoaringEncoder::new(file)?),
+        }?;
+        Ok(list.check()?)
+    })?;
+
+    let version = term.version();
+    let compacted = Term {
+        generation: 0,
+        records,
+        built_at: Utc::now(),
+        deltas: vec![],
+        ..term.clone()
+    };
+    // снимок не должен препятствовать удалению вытесненных поколений
+    drop(snapshot);
+    let published = update_manifest(dir, GC_GRACE_PERIOD, |manifest, now| {
+        if manifest.terms.get(name).map(Term::version) != Some(version) {
+            return Ok(false);
+        }
+        publish(dir, manifest, name, file, compacted, now)
+    })?;
+    if published {
+        info!("Term compacted (name: {}, records: {})", name, records);
+    } else {
+        warn!(
+            "Term {} was updated concurrently, compaction discarded",
+            name
+        );
+    }
+    Ok(())
+}
+
+/// Отслеживает изменение файлов, указанных в запросах ([`Query::watched_file`])
+///
+/// Файл считается измененным, если изменилось время его модификации или размер.
+struct FileWatcher(HashMap<String, Option<(SystemTime, u64)>>);
+
+impl FileWatcher {
+    fn new<Q: Query>(queries: &[Q]) -> Self {
+        let files = queries
+            .iter()
+            .filter_map(|q| Some((q.name().to_string(), file_version(q.watched_file()?))))
+            .collect();
+        Self(files)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Возвращает запросы, файлы которых изменились с момента предыдущей проверки
+    fn changed<'a, Q: Query>(&mut self, queries: &'a [Q]) -> Vec<&'a Q> {
+        let mut changed = vec![];
+        for q in queries {
+            if let (Some(path), Some(seen)) = (q.watched_file(), self.0.get_mut(q.name())) {
+                let version = file_version(path);
+                if version.is_some() && version != *seen {
+                    changed.push(q);
+                }
+                *seen = version;
+            }
+        }
+        changed
     }
 }
 
+fn file_version(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = path.metadata().ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
 /// Запланированное выполнение запроса
 ///
 /// Содержит запрос и время когда этот запрос по плану должен быть выполнен. Эту
@@ -196,16 +495,552 @@ impl<Q> PartialEq for ScheduledQuery<Q> {
     }
 }
 
-#[context("Reading config: {}", path.display())]
-fn read_config(path: &PathBuf) -> Result<Config> {
-    let file = File::open(path)?;
-    let config = serde_yaml::from_reader(file)?;
-    Ok(config)
+/// Публикует записанный во временный файл терм как его новое поколение
+///
+/// Под блокировкой манифеста файл переименовывается в файл следующего поколения, после чего манифест
+/// атомарно заменяется. Там же удаляются файлы поколений, вытесненных более чем `grace` назад.
+#[context("Publishing term {}", name)]
+fn publish_term(
+    dir: &Path,
+    name: &str,
+    file: NamedTempFile,
+    term: Term,
+    grace: Duration,
+) -> Result<()> {
+    update_manifest(dir, grace, |manifest, now| {
+        publish(dir, manifest, name, file, term, now)
+    })?;
+    Ok(())
+}
+
+fn publish(
+    dir: &Path,
+    manifest: &mut Manifest,
+    name: &str,
+    file: NamedTempFile,
+    mut term: Term,
+    now: DateTime<Utc>,
+) -> Result<bool> {
+    term.generation = manifest.next_generation(name);
+    file.persist_noclobber(term_path(dir, name, term.generation))?;
+    manifest.publish(name, term, now);
+    Ok(true)
+}
+
+/// Публикует дельту терма из временных файлов добавленных и удаленных идентификаторов
+///
+/// Дельта публикуется, только если поколение терма все еще равно `version`, то есть терм не был
+/// перестроен или дополнен с момента вычисления дельты. Возвращает `true`, если дельта опубликована.
+#[context("Publishing delta of term {}", name)]
+fn publish_delta(
+    dir: &Path,
+    name: &str,
+    version: u64,
+    (additions, removals): (NamedTempFile, NamedTempFile),
+    mut delta: Delta,
+    grace: Duration,
+) -> Result<bool> {
+    update_manifest(dir, grace, |manifest, _| {
+        if manifest.terms.get(name).map(Term::version) != Some(version) {
+            return Ok(false);
+        }
+        delta.generation = manifest.next_generation(name);
+        let (additions_path, removals_path) = delta_paths(dir, name, delta.generation);
+        additions.persist_noclobber(additions_path)?;
+        removals.persist_noclobber(removals_path)?;
+        Ok(manifest.publish_delta(name, delta))
+    })
+}
+
+/// Изменяет манифест функцией `f` под блокировкой
+///
+/// `f` возвращает `false`, если манифест не был изменен. Измененный манифест атомарно заменяется с новой
+/// эпохой, после чего удаляются файлы поколений, вытесненных более чем `grace` назад. Поколения, которые
+/// могут использовать читатели снимков предыдущих эпох, удаляются при одном из следующих обновлений.
+fn update_manifest(
+    dir: &Path,
+    grace: Duration,
+    f: impl FnOnce(&mut Manifest, DateTime<Utc>) -> Result<bool>,
+) -> Result<bool> {
+    let _lock = lock_manifest(dir)?;
+    let mut manifest = Manifest::load(dir)?;
+
+    let now = Utc::now();
+    if !f(&mut manifest, now)? {
+        return Ok(false);
+    }
+    sync_dir(dir)?;
+    manifest.epoch += 1;
+    let save = |manifest: &Manifest| {
+        write_atomically(&dir.join(MANIFEST_FILE), |file| {
+            Ok(serde_yaml::to_writer(file, manifest)?)
+        })
+    };
+    save(&manifest)?;
+
+    // блокировки проверяются после публикации манифеста: новые читатели уже загрузят новую эпоху
+    let oldest_reader = manifest::oldest_reader_epoch(dir, manifest.epoch)?;
+    let expired = manifest.take_expired(now, grace, oldest_reader);
+    if !expired.is_empty() {
+        save(&manifest)?;
+    }
+
+    for retired in expired {
+        for path in generation_paths(dir, &retired.name, retired.generation) {
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => warn!("Unable to remove {}: {}", path.display(), e),
+                Ok(_) => debug!("Removed {}", path.display()),
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// Захватывает эксклюзивную блокировку манифеста, которая снимается при закрытии возвращенного файла
+///
+/// Блокировка защищает манифест от одновременного обновления как из разных потоков, так и из разных
+/// процессов (например, `tindex index` и `tindex update`).
+pub(super) fn lock_manifest(dir: &Path) -> Result<File> {
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(manifest::LOCK_FILE))?;
+    lock.lock()?;
+    Ok(lock)
+}
+
+/// Записывает содержимое функцией `f` во временный файл в директории `dir` и выполняет `fsync`
+///
+/// Временный файл удаляется, если он не был переименован (например, из-за ошибки записи).
+fn write_temp(dir: &Path, f: impl FnOnce(&File) -> Result<()>) -> Result<NamedTempFile> {
+    let tmp = NamedTempFile::new_in(dir)?;
+    f(tmp.as_file())?;
+    tmp.as_file().sync_all()?;
+    Ok(tmp)
+}
+
+/// Атомарно заменяет файл `path` содержимым, записанным функцией `f`
+///
+/// Данные пишутся во временный файл в той же директории, который после `fsync` переименовывается в `path`.
+/// Читатели видят либо старую, либо новую версию файла целиком. Если запись прервана, предыдущая
+/// версия файла остается нетронутой.
+#[context("Writing {}", path.display())]
+pub(super) fn write_atomically(path: &Path, f: impl FnOnce(&File) -> Result<()>) -> Result<()> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    write_temp(dir, f)?.persist(path)?;
+    sync_dir(dir)
+}
+
+/// `fsync` директории необходим чтобы переименование файла пережило сбой
+pub(super) fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
 }
 
 fn write(rows: impl IntoIterator<Item = u64>, mut sink: impl Encoder) -> Result<()> {
     for id in rows {
         sink.write(id)?;
     }
+    sink.finish()?;
     Ok(())
 }
+
+fn write_sorted(sorter: ExternalSorter, mut sink: impl Encoder) -> Result<u64> {
+    let size = sorter.write_to(&mut sink)?;
+    sink.finish()?;
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::files::{FileQuery, FilesDatabase};
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn check_failed_write_keeps_previous_version() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("term.idx");
+
+        write_atomically(&path, |file| write(1..10, BlockEncoder::new(file)?))?;
+        let result = write_atomically(&path, |file| {
+            let mut encoder = BlockEncoder::new(file)?;
+            encoder.write_values(20..30)?;
+            bail!("Connection lost")
+        });
+        assert!(result.is_err());
+
+        assert_eq!(
+            FileDecoder::open(&path)?.to_vec(),
+            (1..10).collect::<Vec<_>>()
+        );
+        // временный файл удален
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn check_publish_generations() -> Result<()> {
+        let dir = tempdir()?;
+        let dir = dir.path();
+
+        for (i, grace) in [GC_GRACE_PERIOD, GC_GRACE_PERIOD, Duration::ZERO]
+            .iter()
+            .enumerate()
+        {
+            let file = write_temp(dir, |file| {
+                write(1..10 + i as u64, BlockEncoder::new(file)?)
+            })?;
+            publish_term(dir, "term", file, Term::for_test(0, 0), *grace)?;
+        }
+
+        let manifest = Manifest::load(dir)?;
+        assert_eq!(manifest.terms["term"].generation, 3);
+        assert!(manifest.retired.is_empty());
+        // вытесненные поколения удалены
+        assert!(!term_path(dir, "term", 1).exists());
+        assert!(!term_path(dir, "term", 2).exists());
+        assert_eq!(
+            FileDecoder::open(term_path(dir, "term", 3))?.to_vec(),
+            (1..12).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_gc_postponed_while_snapshot_in_use() -> Result<()> {
+        let dir = tempdir()?;
+        let dir = dir.path();
+        let publish = |values| -> Result<()> {
+            let file = write_temp(dir, |file| write(values, BlockEncoder::new(file)?))?;
+            publish_term(dir, "term", file, Term::for_test(0, 0), Duration::ZERO)
+        };
+
+        publish(1..10)?;
+        let index = DirectoryIndex::new(dir.to_path_buf());
+        let snapshot = index.snapshot()?;
+        publish(1..20)?;
+        publish(1..30)?;
+        // снимок ссылается на первое поколение
+        assert!(term_path(dir, "term", 1).exists());
+        assert_eq!(
+            snapshot.lookup("term")?.to_vec(),
+            (1..10).collect::<Vec<_>>()
+        );
+
+        drop(snapshot);
+        publish(1..40)?;
+        for generation in 1..=3 {
+            assert!(!term_path(dir, "term", generation).exists());
+        }
+        assert!(Manifest::load(dir)?.retired.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn check_gc_with_continuous_snapshots() -> Result<()> {
+        let dir = tempdir()?;
+        let dir = dir.path();
+        let publish = |values| -> Result<()> {
+            let file = write_temp(dir, |file| write(values, BlockEncoder::new(file)?))?;
+            publish_term(dir, "term", file, Term::for_test(0, 0), Duration::ZERO)
+        };
+
+        publish(1..10)?;
+        let index = DirectoryIndex::new(dir.to_path_buf());
+        // каждый следующий снимок создается до того, как освобождается предыдущий
+        let mut snapshot = index.snapshot()?;
+        for generation in 2..=5 {
+            publish(1..10 + generation)?;
+            snapshot = index.snapshot()?;
+        }
+        publish(1..20)?;
+
+        // удалены все поколения, кроме используемого последним снимком
+        for generation in 1..=4 {
+            assert!(!term_path(dir, "term", generation).exists());
+        }
+        assert_eq!(
+            snapshot.lookup("term")?.to_vec(),
+            (1..15).collect::<Vec<_>>()
+        );
+        let retired = Manifest::load(dir)?.retired;
+        assert_eq!(
+            retired.iter().map(|r| r.generation).collect::<Vec<_>>(),
+            vec![5]
+        );
+
+        drop(snapshot);
+        publish(1..30)?;
+        assert!(!term_path(dir, "term", 5).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn check_update_from_sqlite() -> Result<()> {
+        let dir = tempdir()?;
+        let index = dir.path().join("index");
+        fs::create_dir(&index)?;
+
+        let db = dir.path().join("users.db");
+        let conn = rusqlite::Connection::open(&db)?;
+        conn.execute_batch(
+            "CREATE TABLE users (id INTEGER, mobile BOOLEAN, paying BOOLEAN);
+             WITH RECURSIVE seq(id) AS (SELECT 1 UNION ALL SELECT id + 1 FROM seq WHERE id < 1000)
+             INSERT INTO users SELECT id, id % 2 = 0, id % 3 = 0 FROM seq;",
+        )?;
+
+        let config = dir.path().join("config.yaml");
+        fs::write(
+            &config,
+            format!(
+                r#"
+                sqlite:
+                - name: local
+                  path: {}
+                  queries:
+                  - name: mobile
+                    schedule: "0 0 * * * *"
+                    sql: SELECT id FROM users WHERE mobile ORDER BY id DESC
+                  - name: paying
+                    schedule: "0 0 * * * *"
+                    sql: SELECT id FROM users WHERE paying
+                    format: roaring
+                derived:
+                - name: mobile_paying
+                  schedule: "0 0 * * * *"
+                  query: mobile & paying
+                "#,
+                db.display()
+            ),
+        )?;
+        let update = |queries: &[&str]| {
+            do_update(UpdateOpts {
+                config: config.clone(),
+                path: index.clone(),
+                queries: queries.iter().map(|q| q.to_string()).collect(),
+            })
+        };
+
+        update(&["mobile", "paying"])?;
+        update(&["mobile_paying"])?;
+
+        let manifest = Manifest::load(&index)?;
+        assert_eq!(manifest.terms["mobile"].records, 500);
+        assert_eq!(manifest.terms["mobile"].database, "local");
+        assert_eq!(manifest.terms["paying"].format, Format::Roaring);
+        assert_eq!(manifest.terms["mobile_paying"].records, 166);
+
+        let read = |name: &str| -> Result<Vec<u64>> {
+            let generation = Manifest::load(&index)?.terms[name].generation;
+            Ok(FileDecoder::open(term_path(&index, name, generation))?.to_vec())
+        };
+        assert_eq!(read("mobile")?, (2..=1000).step_by(2).collect::<Vec<_>>());
+        assert_eq!(
+            read("mobile_paying")?,
+            (6..=1000).step_by(6).collect::<Vec<_>>()
+        );
+
+        conn.execute("DELETE FROM users WHERE id > 10", [])?;
+        update(&["mobile"])?;
+        let manifest = Manifest::load(&index)?;
+        assert_eq!(manifest.terms["mobile"].generation, 2);
+        assert_eq!(read("mobile")?, vec![2, 4, 6, 8, 10]);
+        Ok(())
+    }
+
+    #[test]
+    fn check_delta_updates() -> Result<()> {
+        let dir = tempdir()?;
+        let index = dir.path().join("index");
+        fs::create_dir(&index)?;
+
+        let db = dir.path().join("users.db");
+        let conn = rusqlite::Connection::open(&db)?;
+        // version – номер последнего изменения пользователя
+        conn.execute_batch(
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, mobile BOOLEAN, version INTEGER);
+             WITH RECURSIVE seq(id) AS (SELECT 1 UNION ALL SELECT id + 1 FROM seq WHERE id < 100)
+             INSERT INTO users SELECT id, id % 2 = 0, id FROM seq;",
+        )?;
+        let change = |user_id: u64, mobile: bool| -> Result<()> {
+            conn.execute(
+                "INSERT OR REPLACE INTO users VALUES (?1, ?2, (SELECT max(version) + 1 FROM users))",
+                (user_id, mobile),
+            )?;
+            Ok(())
+        };
+
+        let config = dir.path().join("config.yaml");
+        fs::write(
+            &config,
+            format!(
+                r#"
+                sqlite:
+                - name: local
+                  path: {}
+                  queries:
+                  - name: mobile
+                    schedule: "0 0 * * * *"
+                    sql: SELECT id FROM users WHERE mobile
+                    delta:
+                      watermark: SELECT max(version) FROM users
+                      additions: >
+                        SELECT id FROM users
+                        WHERE version > {{watermark}} AND version <= {{until}} AND mobile
+                      removals: >
+                        SELECT id FROM users
+                        WHERE version > {{watermark}} AND version <= {{until}} AND NOT mobile
+                      compact_after: 2
+                  - name: changed
+                    schedule: "0 0 * * * *"
+                    sql: SELECT id FROM users
+                    delta:
+                      watermark: SELECT max(version) FROM users
+                      additions: SELECT id FROM users WHERE version > {{watermark}}
+                      removals: SELECT id FROM users WHERE version > {{watermark}}
+                "#,
+                db.display()
+            ),
+        )?;
+        let update = |name: &str| {
+            do_update(UpdateOpts {
+                config: config.clone(),
+                path: index.clone(),
+                queries: vec![name.to_string()],
+            })
+        };
+        let lookup = || -> Result<Vec<u64>> {
+            let index = DirectoryIndex::new(index.clone());
+            Ok(index.snapshot()?.lookup("mobile")?.to_vec())
+        };
+        let expected = |conn: &rusqlite::Connection| -> Result<Vec<u64>> {
+            let mut statement = conn.prepare("SELECT id FROM users WHERE mobile ORDER BY id")?;
+            let ids = statement.query_map([], |row| row.get(0))?;
+            Ok(ids.collect::<rusqlite::Result<_>>()?)
+        };
+
+        update("mobile")?;
+        let term = &Manifest::load(&index)?.terms["mobile"];
+        assert_eq!((term.generation, term.watermark), (1, Some(100)));
+        assert!(term.deltas.is_empty());
+
+        change(2, false)?;
+        change(101, true)?;
+        update("mobile")?;
+        let term = &Manifest::load(&index)?.terms["mobile"];
+        assert_eq!((term.version(), term.watermark), (2, Some(102)));
+        assert_eq!((term.deltas[0].additions, term.deltas[0].removals), (1, 1));
+        assert_eq!(lookup()?, expected(&conn)?);
+        let (additions, removals) = delta_paths(&index, "mobile", 2);
+        assert!(additions.exists() && removals.exists());
+
+        // изменений нет, новая дельта не создается
+        update("mobile")?;
+        assert_eq!(Manifest::load(&index)?.terms["mobile"].version(), 2);
+
+        // вторая дельта приводит к компактификации
+        change(3, true)?;
+        change(4, false)?;
+        update("mobile")?;
+        let manifest = Manifest::load(&index)?;
+        let term = &manifest.terms["mobile"];
+        assert_eq!((term.generation, term.watermark), (4, Some(104)));
+        assert!(term.deltas.is_empty());
+        assert_eq!(term.records, expected(&conn)?.len() as u64);
+        assert_eq!(lookup()?, expected(&conn)?);
+        let retired = manifest.retired.iter().map(|r| r.generation);
+        assert_eq!(retired.collect::<Vec<_>>(), vec![1, 2, 3]);
+
+        // идентификатор удален и снова добавлен в пределах одного окна
+        change(6, false)?;
+        change(6, true)?;
+        change(8, false)?;
+        update("mobile")?;
+        let term = &Manifest::load(&index)?.terms["mobile"];
+        assert_eq!((term.version(), term.watermark), (5, Some(107)));
+        assert_eq!((term.deltas[0].additions, term.deltas[0].removals), (1, 1));
+        assert!(lookup()?.contains(&6));
+        assert_eq!(lookup()?, expected(&conn)?);
+
+        // противоречивые запросы не приводят к публикации дельты
+        update("changed")?;
+        change(10, true)?;
+        let error = update("changed").unwrap_err();
+        assert!(format!("{:#}", error).contains("both additions and removals"));
+        assert_eq!(Manifest::load(&index)?.terms["changed"].version(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn check_invalid_and_duplicate_ids() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("ids.txt");
+        fs::write(&path, format!("3\n1\n3\n0\n2\n{}\n-4\n1\n", NO_DOC))?;
+        let config = format!(
+            r#"
+            name: exports
+            queries:
+            - name: ids
+              schedule: "0 0 * * * *"
+              path: {}
+              kind: ids
+            "#,
+            path.display(),
+        );
+        let db: FilesDatabase = serde_yaml::from_str(&config)?;
+        run_query(&mut db.connect()?, &db.list_queries()[0], dir.path())?;
+
+        let manifest = Manifest::load(dir.path())?;
+        assert_eq!(manifest.terms["ids"].records, 3);
+        assert_eq!(
+            FileDecoder::open(term_path(dir.path(), "ids", 1))?.to_vec(),
+            vec![1, 2, 3]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_file_watcher() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("ids.txt");
+        let config = format!(
+            r#"
+            name: exports
+            queries:
+            - name: watched
+              schedule: "0 0 * * * *"
+              path: {}
+              kind: ids
+              watch: true
+            - name: unwatched
+              schedule: "0 0 * * * *"
+              path: {}
+              kind: ids
+            "#,
+            path.display(),
+            path.display(),
+        );
+        let db: FilesDatabase = serde_yaml::from_str(&config)?;
+        let queries = db.list_queries();
+        let mut watcher = FileWatcher::new(queries);
+        let names = |changed: Vec<&FileQuery>| {
+            changed
+                .iter()
+                .map(|q| q.name().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        // файл еще не создан
+        assert!(watcher.changed(queries).is_empty());
+        fs::write(&path, "1\n")?;
+        assert_eq!(names(watcher.changed(queries)), vec!["watched"]);
+        assert!(watcher.changed(queries).is_empty());
+        fs::write(&path, "1\n2\n")?;
+        assert_eq!(names(watcher.changed(queries)), vec!["watched"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,340 @@
+//! Производные термы
+//!
+//! Производный терм ([`DerivedTerm`]) – это результат выражения над другими термами индекса, сохраненный
+//! индексатором как обычный терм. В манифесте вместе с ним записываются поколения входных термов, по которым
+//! он был вычислен. Воркер периодически перечитывает манифест и пересчитывает терм, если поколение любого из
+//! входных термов изменилось, а также по расписанию. Производные термы могут зависеть друг от друга, поэтому
+//! они вычисляются в порядке зависимостей: после всех производных термов, которые они используют.
+use super::{publish_term, write, write_temp};
+use crate::{
+    config::{DerivedTerm, Format},
+    manifest::{sql_hash, Manifest, Term, GC_GRACE_PERIOD},
+    prelude::*,
+    query::{parse_query, query_terms},
+    views::Views,
+    DirectoryIndex,
+};
+use chrono::{DateTime, Utc};
+use fn_error_context::context;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    iter,
+    path::{Path, PathBuf},
+    thread::sleep,
+    time::Duration,
+};
+use tindex_core::{
+    encoding::{block::BlockEncoder, roaring::RoaringEncoder},
+    NO_DOC,
+};
+
+/// Значение поля `database` в манифесте для производных термов
+pub const DERIVED_DATABASE: &str = "derived";
+
+/// Периодичность проверки манифеста на изменение входных термов
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Производный терм вместе с состоянием его пересчета
+struct Derived {
+    term: DerivedTerm,
+    /// термы, используемые выражением
+    inputs: BTreeSet<String>,
+    next_run: Option<DateTime<Utc>>,
+    /// поколения входных термов при последней попытке вычисления
+    seen: Option<BTreeMap<String, u64>>,
+}
+
+/// Пересчитывает производные термы по расписанию и при изменении входных термов
+pub fn worker(terms: Vec<DerivedTerm>, views: Views, path: PathBuf) -> Result<()> {
+    let index = DirectoryIndex::new(path.clone()).with_views(views.clone());
+    let mut derived = prepare(terms, &views, &Manifest::load(&path)?)?;
+    loop {
+        let now = Utc::now();
+        for d in derived.iter_mut() {
+            // предыдущие термы могли обновить входные термы текущего
+            let manifest = Manifest::load(&path)?;
+            let current = generations(&d.inputs, &manifest);
+            let scheduled = d.next_run.is_some_and(|time| time <= now);
+            if !scheduled && d.seen.as_ref() == Some(&current) {
+                continue;
+            }
+            d.seen = Some(match materialize(&index, &d.term, &d.inputs) {
+                Ok(inputs) => inputs,
+                Err(e) => {
+                    // входные термы могут быть еще не построены, повторим после их изменения
+                    warn!("{:#}", e);
+                    current
+                }
+            });
+            if scheduled {
+                d.next_run = next_run(&d.term);
+            }
+        }
+        sleep(POLL_INTERVAL);
+    }
+}
+
+/// Однократно пересчитывает переданные производные термы в порядке зависимостей
+pub fn update(terms: Vec<DerivedTerm>, views: Views, path: &Path) -> Result<()> {
+    let index = DirectoryIndex::new(path.to_path_buf()).with_views(views.clone());
+    for d in prepare(terms, &views, &Manifest::load(path)?)? {
+        materialize(&index, &d.term, &d.inputs)?;
+    }
+    Ok(())
+}
+
+fn prepare(terms: Vec<DerivedTerm>, views: &Views, manifest: &Manifest) -> Result<Vec<Derived>> {
+    let mut derived = vec![];
+    for term in terms {
+        let inputs = query_terms(&term.query, views)
+            .with_context(|| format!("Parsing derived term {}", term.name))?;
+        let seen = manifest.terms.get(&term.name).map(|t| t.inputs.clone());
+        derived.push(Derived {
+            next_run: next_run(&term),
+            term,
+            inputs,
+            seen,
+        });
+    }
+    let order = check_cycles(&derived)?
+        .into_iter()
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    derived.sort_by_key(|d| order.iter().position(|name| *name == d.term.name));
+    Ok(derived)
+}
+
+fn next_run(term: &DerivedTerm) -> Option<DateTime<Utc>> {
+    let next = term.schedule.upcoming(Utc).next();
+    if let Some(time) = next {
+        info!("Derived term {} next execution is {}", term.name, time);
+    }
+    next
+}
+
+/// Производные термы не должны зависеть сами от себя: иначе каждое вычисление приводило бы к следующему
+///
+/// Возвращает имена производных термов в порядке зависимостей: каждый терм следует после всех производных
+/// термов, которые он использует.
+fn check_cycles(derived: &[Derived]) -> Result<Vec<&str>> {
+    let graph = derived
+        .iter()
+        .map(|d| (d.term.name.as_str(), &d.inputs))
+        .collect::<BTreeMap<_, _>>();
+
+    fn visit<'a>(
+        name: &'a str,
+        graph: &BTreeMap<&'a str, &'a BTreeSet<String>>,
+        stack: &mut Vec<&'a str>,
+        done: &mut HashSet<&'a str>,
+        order: &mut Vec<&'a str>,
+    ) -> Result<()> {
+        if let Some(start) = stack.iter().position(|n| *n == name) {
+            let path = stack[start..].join(" -> ");
+            return Err(DerivedCycle(format!("{} -> {}", path, name)).into());
+        }
+        if done.contains(name) {
+            return Ok(());
+        }
+        if let Some(inputs) = graph.get(name) {
+            stack.push(name);
+            for input in inputs.iter() {
+                visit(input, graph, stack, done, order)?;
+            }
+            stack.pop();
+            // все производные входные термы уже добавлены в порядок
+            order.push(name);
+        }
+        done.insert(name);
+        Ok(())
+    }
+
+    let (mut done, mut order) = (HashSet::new(), vec![]);
+    for name in graph.keys() {
+        visit(name, &graph, &mut vec![], &mut done, &mut order)?;
+    }
+    Ok(order)
+}
+
+/// Текущие поколения входных термов с учетом дельт. Термы, отсутствующие в манифесте, не включаются
+fn generations(inputs: &BTreeSet<String>, manifest: &Manifest) -> BTreeMap<String, u64> {
+    inputs
+        .iter()
+        .filter_map(|name| Some((name.clone(), manifest.terms.get(name)?.version())))
+        .collect()
+}
+
+/// Вычисляет выражение производного терма и публикует результат как новое поколение терма
+///
+/// Возвращает поколения входных термов, по которым терм был вычислен.
+#[context("Materializing derived term {}", term.name)]
+fn materialize(
+    index: &DirectoryIndex,
+    term: &DerivedTerm,
+    inputs: &BTreeSet<String>,
+) -> Result<BTreeMap<String, u64>> {
+    info!("Derived term run (name: {})", term.name);
+    let snapshot = index.snapshot()?;
+    let inputs = generations(inputs, snapshot.manifest());
+
+    let mut list = parse_query(&term.query, &snapshot)?;
+    let mut records = 0u64;
+    let path = index.path();
+    let file = write_temp(path, |file| {
+        let ids = iter::from_fn(|| Some(list.next()).filter(|id| *id != NO_DOC))
+            .inspect(|_| records += 1);
+        match term.format {
+            Format::Block => write(ids, BlockEncoder::new(file)?),
+            Format::Roaring => write(ids, RoaringEncoder::new(file)?),
+        }?;
+        // неполный результат из-за поврежденного входного терма не должен быть опубликован
+        Ok(list.check()?)
+    })?;
+
+    let built = Term {
+        generation: 0,
+        records,
+        database: DERIVED_DATABASE.to_string(),
+        sql_hash: sql_hash(&term.query),
+        built_at: Utc::now(),
+        format: term.format,
+        format_version: term.format.version(),
+        inputs: inputs.clone(),
+        watermark: None,
+        deltas: vec![],
+    };
+    // снимок не должен препятствовать удалению вытесненных поколений
+    drop(snapshot);
+    publish_term(path, &term.name, file, built, GC_GRACE_PERIOD)?;
+    info!(
+        "Derived term finished (name: {}, records: {})",
+        term.name, records
+    );
+    Ok(inputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Index;
+    use cron::Schedule;
+    use std::str::FromStr;
+    use tempfile::tempdir;
+    use tindex_core::PostingListDecoder;
+
+    #[test]
+    fn check_materialize() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path();
+        publish(path, "a", 1..100)?;
+        publish(path, "b", 50..200)?;
+
+        let views = Views::from([("ab", "a & b")]);
+        let index = DirectoryIndex::new(path.to_path_buf()).with_views(views.clone());
+        let derived = prepare(
+            vec![derived("c", "ab - b_excluded")],
+            &views,
+            &Manifest::load(path)?,
+        )?;
+        assert_eq!(
+            derived[0].inputs,
+            BTreeSet::from(["a".into(), "b".into(), "b_excluded".into()])
+        );
+
+        // входной терм еще не построен
+        assert!(materialize(&index, &derived[0].term, &derived[0].inputs).is_err());
+
+        publish(path, "b_excluded", 90..95)?;
+        let inputs = materialize(&index, &derived[0].term, &derived[0].inputs)?;
+        assert_eq!(
+            inputs,
+            BTreeMap::from([("a".into(), 1), ("b".into(), 1), ("b_excluded".into(), 1)])
+        );
+
+        let manifest = Manifest::load(path)?;
+        let term = &manifest.terms["c"];
+        assert_eq!(term.database, DERIVED_DATABASE);
+        assert_eq!(term.records, 45);
+        assert_eq!(term.inputs, inputs);
+
+        let expected = (50..90).chain(95..100).collect::<Vec<_>>();
+        assert_eq!(index.snapshot()?.lookup("c")?.to_vec(), expected);
+
+        // новое поколение входного терма
+        publish(path, "a", 1..60)?;
+        let manifest = Manifest::load(path)?;
+        assert_ne!(generations(&derived[0].inputs, &manifest), inputs);
+        Ok(())
+    }
+
+    #[test]
+    fn check_update_in_dependency_order() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path();
+        publish(path, "a", 1..100)?;
+        publish(path, "b", 50..200)?;
+
+        // x зависит от y, но указан в конфигурации первым
+        let terms = vec![derived("x", "y - b"), derived("y", "a | b")];
+        update(terms, Views::default(), path)?;
+
+        let manifest = Manifest::load(path)?;
+        assert_eq!(
+            manifest.terms["x"].inputs["y"],
+            manifest.terms["y"].generation
+        );
+        let index = DirectoryIndex::new(path.to_path_buf());
+        assert_eq!(
+            index.snapshot()?.lookup("x")?.to_vec(),
+            (1..50).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_cycles_detection() -> Result<()> {
+        let manifest = Manifest::default();
+        let views = Views::default();
+        let terms = vec![
+            derived("x", "a & y"),
+            derived("y", "z | b"),
+            derived("z", "x - c"),
+        ];
+        let error = prepare(terms, &views, &manifest).err().unwrap();
+        assert!(
+            matches!(error.downcast_ref(), Some(DerivedCycle(path)) if path == "x -> y -> z -> x")
+        );
+
+        let error = prepare(vec![derived("x", "x | a")], &views, &manifest)
+            .err()
+            .unwrap();
+        assert!(matches!(error.downcast_ref(), Some(DerivedCycle(path)) if path == "x -> x"));
+
+        // цепочка без циклов, термы упорядочиваются по зависимостям
+        let terms = vec![
+            derived("x", "a & y"),
+            derived("w", "x | y"),
+            derived("y", "b | c"),
+        ];
+        let names = prepare(terms, &views, &manifest)?
+            .into_iter()
+            .map(|d| d.term.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["y", "x", "w"]);
+        Ok(())
+    }
+
+    fn publish(path: &Path, name: &str, values: std::ops::Range<u64>) -> Result<()> {
+        let file = write_temp(path, |file| write(values, BlockEncoder::new(file)?))?;
+        publish_term(path, name, file, Term::for_test(0, 0), GC_GRACE_PERIOD)
+    }
+
+    fn derived(name: &str, query: &str) -> DerivedTerm {
+        DerivedTerm {
+            name: name.to_string(),
+            schedule: Schedule::from_str("0 0 * * * *").unwrap(),
+            query: query.to_string(),
+            format: Format::Block,
+        }
+    }
+}
@@ -1,3 +1,18 @@
+use crate::{config::Config, prelude::*, views::Views};
+use std::path::Path;
+
+pub mod count;
 pub mod indexer;
 pub mod query;
 pub mod serve;
+pub mod verify;
+
+/// Читает и проверяет [views](crate::views) из конфигурации, если путь к ней указан
+fn read_views(config: Option<&Path>) -> Result<Views> {
+    let views = match config {
+        Some(path) => Config::read(path)?.views,
+        None => Views::default(),
+    };
+    views.validate().context("Invalid views in config")?;
+    Ok(views)
+}
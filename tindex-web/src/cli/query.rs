@@ -1,4 +1,9 @@
-use crate::{prelude::*, query::parse_query, DirectoryIndex};
+use crate::{
+    cli::read_views,
+    prelude::*,
+    query::{explain_query, parse_query},
+    DirectoryIndex, Universe,
+};
 use clap::Parser;
 use std::path::PathBuf;
 use tindex_core::NO_DOC;
@@ -9,15 +14,34 @@ pub struct Opts {
     /// path to an index
     path: PathBuf,
 
+    /// universe for complement operator: term name or id range (eg. "1..1000000")
+    #[clap(long)]
+    universe: Option<Universe>,
+
+    /// config with named queries (views)
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// print query plan instead of running the query
+    #[clap(long)]
+    explain: bool,
+
     /// query to run (eg. "crit1 & crit2")
     query: String,
 }
 
 pub async fn main(opts: Opts) -> Result<()> {
-    let index = DirectoryIndex(opts.path);
+    let index = DirectoryIndex::new(opts.path)
+        .with_universe(opts.universe)
+        .with_views(read_views(opts.config.as_deref())?);
+    let snapshot = index.snapshot()?;
 
     let query = opts.query;
-    let mut list = parse_query(&query, &index)?;
+    if opts.explain {
+        print!("{}", explain_query(&query, &snapshot)?);
+        return Ok(());
+    }
+    let mut list = parse_query(&query, &snapshot)?;
     loop {
         let doc_id = list.next();
         if doc_id == NO_DOC {
@@ -25,5 +49,6 @@ pub async fn main(opts: Opts) -> Result<()> {
         }
         println!("{}", doc_id);
     }
+    list.check()?;
     Ok(())
 }
@@ -1,18 +1,36 @@
-use crate::{prelude::*, query::parse_query, DirectoryIndex};
+use crate::{
+    cli::read_views,
+    prelude::*,
+    query::{explain_query, parse_query},
+    DirectoryIndex, Universe,
+};
 use clap::Parser;
-use rocket::{get, http::Status, routes, State};
-use std::{ops::Deref, path::PathBuf};
-use tindex_core::NO_DOC;
+use rocket::{get, http::Status, response::stream::TextStream, routes, State};
+use std::path::PathBuf;
+use tindex_core::{PostingList, NO_DOC};
 
 #[derive(Parser, Debug)]
 #[clap(about = "Run REST API HTTP-server for a given index")]
 pub struct Opts {
     /// path to an index
     path: PathBuf,
+
+    /// universe for complement operator: term name or id range (eg. "1..1000000")
+    #[clap(long)]
+    universe: Option<Universe>,
+
+    /// config with named queries (views)
+    #[clap(long)]
+    config: Option<PathBuf>,
 }
 
+mod json;
+
 type HttpResult<T> = std::result::Result<T, Status>;
 
+/// Количество идентификаторов в одном фрагменте потокового ответа
+const CHUNK_SIZE: usize = 1024;
+
 mod app {
     use super::DirectoryIndex;
 
@@ -20,39 +38,203 @@ mod app {
 }
 
 pub async fn main(opts: Opts) -> Result<()> {
-    let index = DirectoryIndex(opts.path);
+    let index = DirectoryIndex::new(opts.path)
+        .with_universe(opts.universe)
+        .with_views(read_views(opts.config.as_deref())?);
 
     let _ = rocket::build()
-        .mount("/", routes![search, check])
+        .mount("/", routes![search, check, count, explain])
+        .mount("/api", json::routes())
         .manage(index)
         .launch()
         .await?;
     Ok(())
 }
 
-#[get("/search?<query>")]
-fn search(query: &str, index: &State<app::Index>) -> HttpResult<String> {
-    let index = index.deref();
-    let mut list = parse_query(query, index).map_err(|_| Status::BadRequest)?;
-    let mut result = String::new();
-    loop {
-        let doc_id = list.next();
-        if doc_id == NO_DOC {
-            break;
+/// Возвращает идентификаторы документов, удовлетворяющих запросу, по одному на строку
+///
+/// Результат отдается потоком фрагментами по [`CHUNK_SIZE`] идентификаторов, поэтому потребление памяти
+/// не зависит от размера результата. Для постраничного чтения используются параметры `limit` (максимальное
+/// количество идентификаторов в ответе) и `after` (вернуть только идентификаторы больше заданного): следующая
+/// страница запрашивается с `after`, равным последнему полученному идентификатору.
+///
+/// Если повреждение индекса обнаружено после отправки части ответа, статус ответа уже не может быть
+/// изменен, поэтому поток завершается строкой `error: <описание>`.
+#[get("/search?<query>&<limit>&<after>")]
+fn search(
+    query: &str,
+    limit: Option<u64>,
+    after: Option<u64>,
+    index: &State<app::Index>,
+) -> HttpResult<TextStream![String]> {
+    let snapshot = index.snapshot().map_err(|_| Status::InternalServerError)?;
+    let list = parse_query(query, &snapshot).map_err(|e| status(&e))?;
+    let mut page = Page::new(list, after, limit);
+    page.check().map_err(|e| status(&e))?;
+    let query = query.to_string();
+    Ok(TextStream! {
+        loop {
+            let chunk = page
+                .by_ref()
+                .take(CHUNK_SIZE)
+                .map(|doc_id| format!("{}\n", doc_id))
+                .collect::<String>();
+            if chunk.is_empty() {
+                break;
+            }
+            yield chunk;
         }
-        result.push_str(&format!("{}\n", doc_id));
-    }
-    Ok(result)
+        if let Err(e) = page.check() {
+            error!("Query {} failed: {:#}", query, e);
+            yield format!("error: {}\n", e);
+        }
+    })
 }
 
 #[get("/check?<query>&<id>")]
 fn check(query: &str, id: u64, index: &State<app::Index>) -> HttpResult<&'static str> {
-    let index = index.deref();
-    let mut list = parse_query(query, index).map_err(|_| Status::BadRequest)?;
-
-    if list.advance(id) == id {
+    let snapshot = index.snapshot().map_err(|_| Status::InternalServerError)?;
+    let mut list = parse_query(query, &snapshot).map_err(|e| status(&e))?;
+    if contains(&mut list, id).map_err(|e| status(&e))? {
         Ok("true")
     } else {
         Ok("false")
     }
 }
+
+#[get("/count?<query>")]
+fn count(query: &str, index: &State<app::Index>) -> HttpResult<String> {
+    let snapshot = index.snapshot().map_err(|_| Status::InternalServerError)?;
+    let mut list = parse_query(query, &snapshot).map_err(|e| status(&e))?;
+    let count = list.count();
+    list.check().map_err(|_| Status::InternalServerError)?;
+    Ok(count.to_string())
+}
+
+#[get("/explain?<query>")]
+fn explain(query: &str, index: &State<app::Index>) -> HttpResult<String> {
+    let snapshot = index.snapshot().map_err(|_| Status::InternalServerError)?;
+    explain_query(query, &snapshot).map_err(|e| status(&e))
+}
+
+/// Проверяет, входит ли идентификатор `id` в результат запроса
+fn contains(list: &mut PostingList, id: u64) -> Result<bool> {
+    // NO_DOC возвращает любой исчерпанный список, а 0 не может быть идентификатором документа
+    if id == 0 || id == NO_DOC {
+        return Err(InvalidQuery(format!("Invalid document id: {}", id)).into());
+    }
+    let found = list.advance(id) == id;
+    list.check()?;
+    Ok(found)
+}
+
+/// Ошибки в тексте запроса – ошибки клиента, остальные (поврежденный индекс, ошибки ввода-вывода) – сервера
+fn status(e: &anyhow::Error) -> Status {
+    match e.downcast_ref::<Error>() {
+        Some(QuerySyntax { .. })
+        | Some(InvalidQuery(_))
+        | Some(TermNotFound(_))
+        | Some(UniverseNotConfigured)
+        | Some(ViewCycle(_)) => Status::BadRequest,
+        _ => Status::InternalServerError,
+    }
+}
+
+/// Страница результата запроса: идентификаторы больше `after`, не более `limit` штук
+struct Page {
+    list: PostingList,
+    remaining: u64,
+    started: bool,
+}
+
+impl Page {
+    fn new(mut list: PostingList, after: Option<u64>, limit: Option<u64>) -> Self {
+        if let Some(after) = after {
+            list.advance(after.saturating_add(1));
+        }
+        Self {
+            list,
+            remaining: limit.unwrap_or(u64::MAX),
+            started: false,
+        }
+    }
+
+    /// Проверяет, что страница не была прервана повреждением индекса
+    fn check(&self) -> Result<()> {
+        Ok(self.list.check()?)
+    }
+}
+
+impl Iterator for Page {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let doc_id = if self.started {
+            self.list.next()
+        } else {
+            self.started = true;
+            self.list.current()
+        };
+        if doc_id == NO_DOC {
+            self.remaining = 0;
+            return None;
+        }
+        self.remaining -= 1;
+        Some(doc_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tindex_core::{DecodeError, RangePostingList};
+
+    #[test]
+    fn check_pagination() {
+        let page = |after, limit| {
+            let list = RangePostingList::new(1..10).into();
+            Page::new(list, after, limit).collect::<Vec<_>>()
+        };
+
+        assert_eq!(page(None, None), (1..10).collect::<Vec<_>>());
+        assert_eq!(page(None, Some(3)), vec![1, 2, 3]);
+        assert_eq!(page(Some(3), Some(3)), vec![4, 5, 6]);
+        assert_eq!(page(Some(7), Some(3)), vec![8, 9]);
+        assert!(page(Some(9), Some(3)).is_empty());
+        assert!(page(Some(u64::MAX), None).is_empty());
+        assert!(page(None, Some(0)).is_empty());
+    }
+
+    #[test]
+    fn check_contains() -> Result<()> {
+        let list = || PostingList::from(RangePostingList::new(1..10));
+
+        assert!(contains(&mut list(), 5)?);
+        assert!(!contains(&mut list(), 10)?);
+        for id in [0, NO_DOC] {
+            let error = contains(&mut list(), id).unwrap_err();
+            assert_eq!(status(&error), Status::BadRequest);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn check_error_status() {
+        let error = |e: anyhow::Error| status(&e);
+
+        assert_eq!(error(TermNotFound("a".into()).into()), Status::BadRequest);
+        assert_eq!(error(UniverseNotConfigured.into()), Status::BadRequest);
+        assert_eq!(
+            error(DecodeError("Block 0 checksum mismatch".into()).into()),
+            Status::InternalServerError
+        );
+        let io = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        assert_eq!(
+            error(anyhow::Error::from(io).context(OpeningIndexFile("a.idx".into()))),
+            Status::InternalServerError
+        );
+    }
+}
@@ -0,0 +1,352 @@
+//! JSON API
+//!
+//! Те же запросы, что и текстовый API, но с ответами в формате JSON. Каждый успешный ответ содержит время
+//! выполнения запроса (`elapsed_ms`). Ошибки возвращаются с соответствующим HTTP-статусом и телом [`ApiError`],
+//! поле `error` которого позволяет клиенту отличить синтаксическую ошибку от отсутствующего терма.
+use super::{app, contains, Page};
+use crate::{prelude::*, query::parse_query};
+use rocket::{
+    get,
+    http::Status,
+    post, routes,
+    serde::{json::Json, Deserialize, Serialize},
+    Route, State,
+};
+use std::time::Instant;
+use tindex_core::{
+    DecodeError, Intersect, PostingList, PostingListDecoder, VecPostingList, NO_DOC,
+};
+
+/// Количество идентификаторов в ответе `/api/search`, если параметр `limit` не задан
+const DEFAULT_LIMIT: u64 = 10_000;
+
+/// Максимальное значение параметра `limit` в `/api/search`. Ответ собирается в памяти целиком, поэтому
+/// большие результаты следует читать постранично или через потоковый `/search`
+const MAX_LIMIT: u64 = 100_000;
+
+type ApiResult<T> = std::result::Result<Json<T>, (Status, Json<ApiError>)>;
+
+pub fn routes() -> Vec<Route> {
+    routes![search, count, check, check_batch]
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct SearchResponse {
+    ids: Vec<u64>,
+    count: usize,
+    /// значение `after` для запроса следующей страницы, если результат не уместился в текущую
+    next_after: Option<u64>,
+    elapsed_ms: f64,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct CountResponse {
+    count: u64,
+    elapsed_ms: f64,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct CheckResponse {
+    matches: bool,
+    elapsed_ms: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct CheckBatchRequest {
+    query: String,
+    /// идентификаторы-кандидаты в произвольном порядке
+    ids: Vec<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct CheckBatchResponse {
+    /// кандидаты, удовлетворяющие запросу, в порядке возрастания
+    matches: Vec<u64>,
+    elapsed_ms: f64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(crate = "rocket::serde", tag = "error", rename_all = "snake_case")]
+enum ApiError {
+    Syntax {
+        message: String,
+        position: usize,
+        line: usize,
+        column: usize,
+    },
+    InvalidQuery {
+        message: String,
+    },
+    TermNotFound {
+        message: String,
+        term: String,
+    },
+    /// индексный файл поврежден, результат запроса не может быть вычислен
+    CorruptedIndex {
+        message: String,
+    },
+    Internal {
+        message: String,
+    },
+}
+
+impl ApiError {
+    fn status(&self) -> Status {
+        match self {
+            ApiError::Syntax { .. } | ApiError::InvalidQuery { .. } => Status::BadRequest,
+            ApiError::TermNotFound { .. } => Status::NotFound,
+            ApiError::CorruptedIndex { .. } | ApiError::Internal { .. } => {
+                Status::InternalServerError
+            }
+        }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        let message = e.root_cause().to_string();
+        if e.is::<DecodeError>() {
+            return ApiError::CorruptedIndex {
+                message: format!("{:#}", e),
+            };
+        }
+        match e.downcast_ref::<Error>() {
+            Some(QuerySyntax {
+                message,
+                position,
+                line,
+                column,
+            }) => ApiError::Syntax {
+                message: message.clone(),
+                position: *position,
+                line: *line,
+                column: *column,
+            },
+            Some(InvalidQuery(_)) | Some(UniverseNotConfigured) | Some(ViewCycle(_)) => {
+                ApiError::InvalidQuery { message }
+            }
+            Some(TermNotFound(term)) => ApiError::TermNotFound {
+                message,
+                term: term.clone(),
+            },
+            _ => ApiError::Internal {
+                message: format!("{:#}", e),
+            },
+        }
+    }
+}
+
+fn api_error(e: anyhow::Error) -> (Status, Json<ApiError>) {
+    let error = ApiError::from(e);
+    (error.status(), Json(error))
+}
+
+fn run_query(query: &str, index: &app::Index) -> Result<PostingList> {
+    parse_query(query, &index.snapshot()?)
+}
+
+fn elapsed_ms(started: Instant) -> f64 {
+    started.elapsed().as_secs_f64() * 1000.
+}
+
+/// Возвращает не более `limit` ([`DEFAULT_LIMIT`] по умолчанию, [`MAX_LIMIT`] максимум) идентификаторов,
+/// больших `after`
+#[get("/search?<query>&<limit>&<after>")]
+fn search(
+    query: &str,
+    limit: Option<u64>,
+    after: Option<u64>,
+    index: &State<app::Index>,
+) -> ApiResult<SearchResponse> {
+    let started = Instant::now();
+    let limit = page_limit(limit).map_err(api_error)?;
+    let list = run_query(query, index).map_err(api_error)?;
+
+    // на один элемент больше, чтобы узнать есть ли следующая страница
+    let mut page = Page::new(list, after, Some(limit + 1));
+    let mut ids = page.by_ref().collect::<Vec<_>>();
+    page.check().map_err(api_error)?;
+    let next_after = if ids.len() as u64 > limit {
+        ids.pop();
+        ids.last().cloned()
+    } else {
+        None
+    };
+    Ok(Json(SearchResponse {
+        count: ids.len(),
+        ids,
+        next_after,
+        elapsed_ms: elapsed_ms(started),
+    }))
+}
+
+fn page_limit(limit: Option<u64>) -> Result<u64> {
+    match limit {
+        Some(limit) if limit > MAX_LIMIT => Err(InvalidQuery(format!(
+            "Limit should not exceed {}, {} given",
+            MAX_LIMIT, limit
+        ))
+        .into()),
+        limit => Ok(limit.unwrap_or(DEFAULT_LIMIT)),
+    }
+}
+
+#[get("/count?<query>")]
+fn count(query: &str, index: &State<app::Index>) -> ApiResult<CountResponse> {
+    let started = Instant::now();
+    let mut list = run_query(query, index).map_err(api_error)?;
+    let count = list.count();
+    list.check().map_err(|e| api_error(e.into()))?;
+    Ok(Json(CountResponse {
+        count,
+        elapsed_ms: elapsed_ms(started),
+    }))
+}
+
+#[get("/check?<query>&<id>")]
+fn check(query: &str, id: u64, index: &State<app::Index>) -> ApiResult<CheckResponse> {
+    let started = Instant::now();
+    let mut list = run_query(query, index).map_err(api_error)?;
+    let matches = contains(&mut list, id).map_err(api_error)?;
+    Ok(Json(CheckResponse {
+        matches,
+        elapsed_ms: elapsed_ms(started),
+    }))
+}
+
+/// Проверяет принадлежность множества идентификаторов результату запроса за один HTTP-запрос
+#[post("/check", data = "<request>")]
+fn check_batch(
+    request: Json<CheckBatchRequest>,
+    index: &State<app::Index>,
+) -> ApiResult<CheckBatchResponse> {
+    let started = Instant::now();
+    let request = request.into_inner();
+    let list = run_query(&request.query, index).map_err(api_error)?;
+    Ok(Json(CheckBatchResponse {
+        matches: matching(list, request.ids).map_err(|e| api_error(e.into()))?,
+        elapsed_ms: elapsed_ms(started),
+    }))
+}
+
+/// Возвращает отсортированный список тех `ids`, которые входят в `list`
+///
+/// Кандидаты сортируются и пересекаются со списком, поэтому `list` продвигается только к кандидатам
+/// и не декодируется целиком.
+fn matching(list: PostingList, mut ids: Vec<u64>) -> std::result::Result<Vec<u64>, DecodeError> {
+    ids.sort_unstable();
+    ids.dedup();
+    // 0 и NO_DOC не могут быть идентификаторами документов
+    ids.retain(|id| *id != 0 && *id != NO_DOC);
+    if ids.is_empty() {
+        return Ok(ids);
+    }
+    Intersect(list, VecPostingList::new(&ids).into()).try_to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DirectoryIndex;
+    use std::fs;
+    use tempfile::tempdir;
+    use tindex_core::RangePostingList;
+
+    #[test]
+    fn check_error_classification() -> Result<()> {
+        let dir = tempdir()?;
+        fs::write(dir.path().join("a.idx"), "1\n2\n3\n")?;
+        let index = DirectoryIndex::new(dir.path().to_path_buf());
+
+        let error = |query| ApiError::from(run_query(query, &index).err().unwrap());
+
+        let syntax = error("a & (b");
+        assert!(matches!(
+            syntax,
+            ApiError::Syntax {
+                position: 6,
+                line: 1,
+                column: 7,
+                ..
+            }
+        ));
+        assert_eq!(syntax.status(), Status::BadRequest);
+
+        let missing = error("a & missing");
+        assert!(matches!(&missing, ApiError::TermNotFound { term, .. } if term == "missing"));
+        assert_eq!(missing.status(), Status::NotFound);
+
+        assert!(matches!(
+            error("atleast(3, a)"),
+            ApiError::InvalidQuery { .. }
+        ));
+        assert!(matches!(error("!a"), ApiError::InvalidQuery { .. }));
+        Ok(())
+    }
+
+    #[test]
+    fn check_batch_matching() -> Result<()> {
+        let list = || RangePostingList::new(10..20).into();
+
+        assert_eq!(
+            matching(list(), vec![25, 3, 15, 10, 15, 19, 0])?,
+            vec![10, 15, 19]
+        );
+        assert!(matching(list(), vec![1, 2, 20])?.is_empty());
+        assert!(matching(list(), vec![])?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn check_invalid_parameters() -> Result<()> {
+        let dir = tempdir()?;
+        fs::write(dir.path().join("a.idx"), "1\n2\n3\n")?;
+        let index = DirectoryIndex::new(dir.path().to_path_buf());
+
+        for id in [0, NO_DOC] {
+            let mut list = run_query("a", &index)?;
+            let error = ApiError::from(contains(&mut list, id).unwrap_err());
+            assert!(matches!(error, ApiError::InvalidQuery { .. }));
+            assert_eq!(error.status(), Status::BadRequest);
+        }
+        assert!(contains(&mut run_query("a", &index)?, 3)?);
+
+        assert_eq!(page_limit(None)?, DEFAULT_LIMIT);
+        assert_eq!(page_limit(Some(MAX_LIMIT))?, MAX_LIMIT);
+        let error = ApiError::from(page_limit(Some(u64::MAX)).unwrap_err());
+        assert_eq!(error.status(), Status::BadRequest);
+        Ok(())
+    }
+
+    #[test]
+    fn check_corrupted_index() -> Result<()> {
+        let dir = tempdir()?;
+        fs::write(dir.path().join("a.idx"), "1\n2\nfoo\n")?;
+        let index = DirectoryIndex::new(dir.path().to_path_buf());
+
+        let mut list = run_query("a", &index)?;
+        assert_eq!(list.count(), 2);
+        let error = ApiError::from(anyhow::Error::from(list.check().unwrap_err()));
+        assert!(matches!(error, ApiError::CorruptedIndex { .. }));
+        assert_eq!(error.status(), Status::InternalServerError);
+        Ok(())
+    }
+
+    #[test]
+    fn check_error_body() {
+        let error = ApiError::TermNotFound {
+            message: "Term not found: b".to_string(),
+            term: "b".to_string(),
+        };
+        let json = rocket::serde::json::to_string(&error).unwrap();
+        assert_eq!(
+            json,
+            r#"{"error":"term_not_found","message":"Term not found: b","term":"b"}"#
+        );
+    }
+}
@@ -0,0 +1,262 @@
+//! Проверка целостности индекса
+//!
+//! Каждый терм индекса полностью декодируется: проверяются контрольные суммы блоков, порядок идентификаторов,
+//! количество идентификаторов в заголовке файла, а для термов из манифеста – формат файла и количество
+//! записей, указанное в манифесте. Файлы дельт терма проверяются так же, как и основной файл.
+//!
+//! С флагом `--quarantine` поврежденные термы исключаются из манифеста, а их файлы переносятся в директорию
+//! [`QUARANTINE_DIR`], чтобы запросы к ним завершались ошибкой [`TermNotFound`] вместо неполного результата.
+use super::indexer::{lock_manifest, sync_dir, write_atomically};
+use crate::{
+    config::Format,
+    manifest::{delta_paths, term_path, Manifest, ReaderLease, Term, MANIFEST_FILE},
+    prelude::*,
+};
+use anyhow::ensure;
+use chrono::Utc;
+use clap::Parser;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tindex_core::{encoding::FileDecoder, segment::Segment};
+
+/// Директория индекса, в которую переносятся файлы поврежденных термов
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+#[derive(Parser, Debug)]
+#[clap(about = "Verify integrity of all terms in an index")]
+pub struct Opts {
+    /// path to an index
+    path: PathBuf,
+
+    /// remove broken terms from the manifest and move their files to the quarantine directory
+    #[clap(long)]
+    quarantine: bool,
+}
+
+pub async fn main(opts: Opts) -> Result<()> {
+    let broken = verify_index(&opts.path)?;
+    if opts.quarantine && !broken.is_empty() {
+        quarantine(&opts.path, &broken)?;
+    }
+    ensure!(broken.is_empty(), "{} broken term(s) found", broken.len());
+    Ok(())
+}
+
+/// Проверяет все термы индекса и возвращает имена и пути файлов поврежденных термов
+fn verify_index(dir: &Path) -> Result<Vec<(String, Vec<PathBuf>)>> {
+    // проверка может длиться долго, файлы термов не должны быть удалены индексатором до ее окончания
+    let (_lease, manifest) = ReaderLease::acquire(dir)?;
+    let mut broken = vec![];
+    for (name, term) in &manifest.terms {
+        let files = term_files(dir, name, term);
+        let result = files.iter().try_for_each(|(path, records)| {
+            verify_term(path, Some((term.format, *records))).map(|_| ())
+        });
+        match result {
+            Ok(_) if term.deltas.is_empty() => println!("{}: ok ({} records)", name, term.records),
+            Ok(_) => println!(
+                "{}: ok ({} records, {} deltas)",
+                name,
+                term.records,
+                term.deltas.len()
+            ),
+            Err(e) => {
+                println!("{}: {:#}", name, e);
+                broken.push((
+                    name.clone(),
+                    files.into_iter().map(|(path, _)| path).collect(),
+                ));
+            }
+        }
+    }
+
+    for name in legacy_terms(dir, &manifest)? {
+        let path = dir.join(format!("{}.idx", name));
+        match verify_term(&path, None) {
+            Ok(records) => println!("{}: ok ({} records)", name, records),
+            Err(e) => {
+                println!("{}: {:#}", name, e);
+                broken.push((name, vec![path]));
+            }
+        }
+    }
+    Ok(broken)
+}
+
+/// Файлы терма из манифеста (основной файл и файлы дельт) и количество идентификаторов в каждом из них
+fn term_files(dir: &Path, name: &str, term: &Term) -> Vec<(PathBuf, u64)> {
+    let mut files = vec![(term_path(dir, name, term.generation), term.records)];
+    for delta in &term.deltas {
+        let (additions, removals) = delta_paths(dir, name, delta.generation);
+        files.push((additions, delta.additions));
+        files.push((removals, delta.removals));
+    }
+    files
+}
+
+/// Термы, построенные до появления манифеста: файлы `{name}.idx`, не относящиеся ни к одному поколению
+fn legacy_terms(dir: &Path, manifest: &Manifest) -> Result<Vec<String>> {
+    let mut names = vec![];
+    for entry in fs::read_dir(dir)? {
+        let file_name = entry?.file_name();
+        let Some(name) = file_name.to_str().and_then(|f| f.strip_suffix(".idx")) else {
+            continue;
+        };
+        let name = name
+            .strip_suffix(".add")
+            .or_else(|| name.strip_suffix(".del"))
+            .unwrap_or(name);
+        let generation = name
+            .rsplit_once('.')
+            .is_some_and(|(_, generation)| generation.parse::<u64>().is_ok());
+        if !generation && !manifest.terms.contains_key(name) {
+            names.push(name.to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Проверяет файл терма и возвращает количество идентификаторов в нем
+///
+/// `expected` – формат файла и количество идентификаторов, указанные в манифесте.
+fn verify_term(path: &Path, expected: Option<(Format, u64)>) -> Result<u64> {
+    let segment = Segment::open(path)?;
+    if let Some((expected_format, _)) = expected {
+        let format = match FileDecoder::new(segment.clone())? {
+            FileDecoder::Block(_) => Some(Format::Block),
+            FileDecoder::Roaring(_) => Some(Format::Roaring),
+            FileDecoder::PlainText(_) => None,
+        };
+        ensure!(
+            format == Some(expected_format),
+            "Manifest expects {:?} format, {:?} found in {}",
+            expected_format,
+            format,
+            path.display()
+        );
+    }
+    let records = FileDecoder::verify(segment)?;
+    if let Some((_, expected_records)) = expected {
+        ensure!(
+            records == expected_records,
+            "Manifest expects {} records, {} found in {}",
+            expected_records,
+            records,
+            path.display()
+        );
+    }
+    Ok(records)
+}
+
+/// Исключает поврежденные термы из манифеста и переносит их файлы в [`QUARANTINE_DIR`]
+fn quarantine(dir: &Path, broken: &[(String, Vec<PathBuf>)]) -> Result<()> {
+    let target = dir.join(QUARANTINE_DIR);
+    fs::create_dir_all(&target)?;
+
+    let _lock = lock_manifest(dir)?;
+    let mut manifest = Manifest::load(dir)?;
+    let now = Utc::now();
+    for (name, paths) in broken {
+        if let Some(term) = manifest.terms.get(name) {
+            // индексатор мог опубликовать новое поколение или дельту терма во время проверки
+            if !term_files(dir, name, term)
+                .into_iter()
+                .map(|(path, _)| path)
+                .eq(paths.iter().cloned())
+            {
+                info!("Term {} was updated during verification, skipping", name);
+                continue;
+            }
+            manifest.retire(name, now);
+        }
+        for path in paths {
+            let file_name = path.file_name().context("Invalid term path")?;
+            fs::rename(path, target.join(file_name))?;
+            warn!("Term {} quarantined: {}", name, path.display());
+        }
+    }
+    manifest.epoch += 1;
+    write_atomically(&dir.join(MANIFEST_FILE), |file| {
+        Ok(serde_yaml::to_writer(file, &manifest)?)
+    })?;
+    sync_dir(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::Delta;
+    use tempfile::tempdir;
+    use tindex_core::encoding::{block::BlockEncoder, Encoder};
+
+    #[test]
+    fn check_verify_and_quarantine() -> Result<()> {
+        let dir = tempdir()?;
+        let dir = dir.path();
+
+        let mut manifest = Manifest::default();
+        for (name, records) in [("good", 9), ("corrupted", 9), ("miscounted", 10)] {
+            let mut encoder = BlockEncoder::create(term_path(dir, name, 1))?;
+            encoder.write_values(1..10)?;
+            encoder.finish()?;
+            manifest.publish(name, Term::for_test(1, records), Utc::now());
+        }
+        // терм с дельтой, файл удаленных идентификаторов которой поврежден
+        let (additions, removals) = delta_paths(dir, "delta", 2);
+        for (path, values) in [
+            (term_path(dir, "delta", 1), 1..10),
+            (additions, 10..11),
+            (removals, 3..5),
+        ] {
+            let mut encoder = BlockEncoder::create(path)?;
+            encoder.write_values(values)?;
+            encoder.finish()?;
+        }
+        manifest.publish("delta", Term::for_test(1, 9), Utc::now());
+        manifest.publish_delta(
+            "delta",
+            Delta {
+                generation: 2,
+                additions: 1,
+                removals: 2,
+                watermark: 1,
+                built_at: Utc::now(),
+            },
+        );
+
+        for corrupted in [
+            term_path(dir, "corrupted", 1),
+            delta_paths(dir, "delta", 2).1,
+        ] {
+            let mut data = fs::read(&corrupted)?;
+            data[30] ^= 1;
+            fs::write(&corrupted, data)?;
+        }
+        write_atomically(&dir.join(MANIFEST_FILE), |file| {
+            Ok(serde_yaml::to_writer(file, &manifest)?)
+        })?;
+        fs::write(dir.join("legacy.idx"), "1\n3\n2\n")?;
+
+        let broken = verify_index(dir)?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(broken, vec!["corrupted", "delta", "miscounted", "legacy"]);
+
+        quarantine(dir, &verify_index(dir)?)?;
+        assert!(verify_index(dir)?.is_empty());
+        let manifest = Manifest::load(dir)?;
+        assert_eq!(manifest.terms.keys().collect::<Vec<_>>(), vec!["good"]);
+        assert_eq!(manifest.next_generation("corrupted"), 2);
+        assert!(dir.join(QUARANTINE_DIR).join("corrupted.1.idx").exists());
+        assert!(dir.join(QUARANTINE_DIR).join("legacy.idx").exists());
+        // все файлы терма перенесены вместе
+        assert!(dir.join(QUARANTINE_DIR).join("delta.1.idx").exists());
+        assert!(dir.join(QUARANTINE_DIR).join("delta.2.add.idx").exists());
+        assert_eq!(manifest.next_generation("delta"), 3);
+        Ok(())
+    }
+}
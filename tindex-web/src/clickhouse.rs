@@ -1,5 +1,5 @@
 use crate::config::{self, Connection};
-use crate::config::{Database, Query};
+use crate::config::{Database, DeltaQuery, Format, Query};
 use crate::prelude::*;
 use clickhouse::Client;
 use cron::Schedule;
@@ -46,10 +46,22 @@ impl Connection for ClickhouseConnection {
         &self.0
     }
 
-    fn execute(&mut self, query: &Self::Query) -> Result<Vec<u64>> {
-        let result = self.1.query(&query.sql).fetch_all::<u64>();
+    fn execute(
+        &mut self,
+        query: &Self::Query,
+        sink: &mut dyn FnMut(u64) -> Result<()>,
+    ) -> Result<()> {
+        self.execute_sql(&query.sql, sink)
+    }
 
-        Ok(futures::executor::block_on(result)?)
+    fn execute_sql(&mut self, sql: &str, sink: &mut dyn FnMut(u64) -> Result<()>) -> Result<()> {
+        let mut cursor = self.1.query(sql).fetch::<u64>()?;
+        futures::executor::block_on(async {
+            while let Some(id) = cursor.next().await? {
+                sink(id)?;
+            }
+            Ok(())
+        })
     }
 }
 
@@ -59,6 +71,10 @@ pub struct ClickhouseQuery {
     #[serde(deserialize_with = "config::schedule_from_string")]
     pub schedule: Schedule,
     pub sql: String,
+    #[serde(default)]
+    pub format: Format,
+    #[serde(default)]
+    pub delta: Option<DeltaQuery>,
 }
 
 impl Query for ClickhouseQuery {
@@ -69,4 +85,16 @@ impl Query for ClickhouseQuery {
     fn schedule(&self) -> &cron::Schedule {
         &self.schedule
     }
+
+    fn format(&self) -> Format {
+        self.format
+    }
+
+    fn sql(&self) -> &str {
+        &self.sql
+    }
+
+    fn delta(&self) -> Option<&DeltaQuery> {
+        self.delta.as_ref()
+    }
 }
@@ -0,0 +1,326 @@
+//! Источник данных из файлов
+//!
+//! Позволяет индексировать выгрузки, которые поставляются в виде файлов, а не таблиц БД:
+//!
+//! ```yaml
+//! files:
+//! - name: exports
+//!   queries:
+//!   - name: churn_risk
+//!     schedule: "0 0 * * * *"
+//!     path: /data/churn_risk.csv
+//!     kind: csv
+//!     column: user_id
+//!     watch: true
+//! ```
+//!
+//! Поддерживаются CSV-файлы с заголовком ([`FileKind::Csv`]), списки идентификаторов по одному на строку
+//! ([`FileKind::Ids`]) и колонки Parquet-файлов ([`FileKind::Parquet`]). Если задан `watch`, запрос
+//! выполняется не только по расписанию, но и при каждом изменении файла.
+use crate::{
+    config::{self, Connection, Database, Format, Query},
+    prelude::*,
+};
+use anyhow::bail;
+use cron::Schedule;
+use fn_error_context::context;
+use parquet::{
+    file::reader::{FileReader, SerializedFileReader},
+    record::Field,
+    schema::types::Type,
+};
+use serde::Deserialize;
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+#[derive(Deserialize, PartialEq, Eq, Debug)]
+pub struct FilesDatabase {
+    name: String,
+    queries: Vec<FileQuery>,
+}
+
+impl Database for FilesDatabase {
+    type Connection = FilesConnection;
+
+    fn connect(&self) -> Result<Self::Connection> {
+        Ok(FilesConnection(self.name.to_owned()))
+    }
+
+    fn list_queries(&self) -> &[FileQuery] {
+        &self.queries[..]
+    }
+}
+
+pub struct FilesConnection(String);
+
+impl Connection for FilesConnection {
+    type Query = FileQuery;
+
+    fn name(&self) -> &str {
+        &self.0
+    }
+
+    #[context("Reading {}", query.path.display())]
+    fn execute(
+        &mut self,
+        query: &FileQuery,
+        sink: &mut dyn FnMut(u64) -> Result<()>,
+    ) -> Result<()> {
+        let column = query.column.as_deref();
+        match query.kind {
+            FileKind::Ids => read_ids(&query.path, sink),
+            FileKind::Csv => read_csv(&query.path, column, sink),
+            FileKind::Parquet => read_parquet(&query.path, column, sink),
+        }
+    }
+}
+
+/// Формат файла с идентификаторами
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    /// Идентификаторы по одному на строку
+    Ids,
+
+    /// CSV-файл с заголовком
+    Csv,
+
+    /// Parquet-файл, колонка целочисленного типа
+    Parquet,
+}
+
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct FileQuery {
+    name: String,
+    #[serde(deserialize_with = "config::schedule_from_string")]
+    schedule: Schedule,
+    path: PathBuf,
+    kind: FileKind,
+    /// колонка с идентификаторами, по умолчанию – первая колонка файла
+    column: Option<String>,
+    #[serde(default)]
+    watch: bool,
+    #[serde(default)]
+    format: Format,
+}
+
+impl Query for FileQuery {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    fn format(&self) -> Format {
+        self.format
+    }
+
+    fn sql(&self) -> &str {
+        self.path.to_str().unwrap_or_default()
+    }
+
+    fn watched_file(&self) -> Option<&Path> {
+        self.watch.then_some(self.path.as_path())
+    }
+}
+
+fn read_ids(path: &Path, sink: &mut dyn FnMut(u64) -> Result<()>) -> Result<()> {
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let line = line.trim();
+        if !line.is_empty() {
+            sink(parse_id(line)?)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_csv(
+    path: &Path,
+    column: Option<&str>,
+    sink: &mut dyn FnMut(u64) -> Result<()>,
+) -> Result<()> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let index = match column {
+        Some(column) => match reader.headers()?.iter().position(|h| h == column) {
+            Some(index) => index,
+            None => bail!("Column {} not found", column),
+        },
+        None => 0,
+    };
+    for (line, record) in reader.records().enumerate() {
+        let record = record?;
+        match record.get(index) {
+            Some(value) => sink(parse_id(value.trim())?)?,
+            // первая строка файла – заголовок
+            None => bail!("Column {} is missing at line {}", index + 1, line + 2),
+        }
+    }
+    Ok(())
+}
+
+fn read_parquet(
+    path: &Path,
+    column: Option<&str>,
+    sink: &mut dyn FnMut(u64) -> Result<()>,
+) -> Result<()> {
+    let reader = SerializedFileReader::new(File::open(path)?)?;
+    let schema = reader.metadata().file_metadata().schema();
+    let field = match column {
+        Some(column) => schema.get_fields().iter().find(|f| f.name() == column),
+        None => schema.get_fields().first(),
+    };
+    let Some(field) = field else {
+        bail!("Column {} not found", column.unwrap_or_default());
+    };
+    // читаем только нужную колонку
+    let projection = Type::group_type_builder(schema.name())
+        .with_fields(vec![Arc::clone(field)])
+        .build()?;
+
+    for row in reader.get_row_iter(Some(projection))? {
+        let row = row?;
+        let id = match row.get_column_iter().next().map(|(_, value)| value) {
+            Some(Field::Long(id)) => config::to_id(*id),
+            Some(Field::Int(id)) => config::to_id(*id as i64),
+            Some(Field::ULong(id)) => *id,
+            Some(Field::UInt(id)) => *id as u64,
+            Some(value) => bail!("Integer identifier expected, {} found", value),
+            None => bail!("Column {} is missing", field.name()),
+        };
+        sink(id)?;
+    }
+    Ok(())
+}
+
+/// Разбирает идентификатор, отрицательные числа преобразуются в недопустимый идентификатор 0
+fn parse_id(value: &str) -> Result<u64> {
+    value
+        .parse()
+        .or_else(|_| value.parse().map(config::to_id))
+        .with_context(|| format!("Invalid identifier: {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::{
+        data_type::Int64Type, file::properties::WriterProperties,
+        file::writer::SerializedFileWriter, schema::parser::parse_message_type,
+    };
+    use std::{fs, str::FromStr};
+    use tempfile::tempdir;
+
+    fn collect(
+        read: impl FnOnce(&mut dyn FnMut(u64) -> Result<()>) -> Result<()>,
+    ) -> Result<Vec<u64>> {
+        let mut ids = vec![];
+        read(&mut |id| {
+            ids.push(id);
+            Ok(())
+        })?;
+        Ok(ids)
+    }
+
+    #[test]
+    fn read_yaml() -> Result<()> {
+        let config: FilesDatabase = serde_yaml::from_str(
+            r#"
+            name: exports
+            queries:
+            - name: churn_risk
+              schedule: "0 0 * * * *"
+              path: /data/churn_risk.csv
+              kind: csv
+              column: user_id
+              watch: true
+            "#,
+        )?;
+        let expected = FilesDatabase {
+            name: "exports".to_string(),
+            queries: vec![FileQuery {
+                name: "churn_risk".to_string(),
+                schedule: Schedule::from_str("0 0 * * * *")?,
+                path: PathBuf::from("/data/churn_risk.csv"),
+                kind: FileKind::Csv,
+                column: Some("user_id".to_string()),
+                watch: true,
+                format: Format::Block,
+            }],
+        };
+        assert_eq!(config, expected);
+        assert_eq!(
+            config.queries[0].watched_file(),
+            Some(Path::new("/data/churn_risk.csv"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_ids() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("ids.txt");
+        fs::write(&path, "3\n1\n\n 2 \n-4\n")?;
+        assert_eq!(collect(|sink| read_ids(&path, sink))?, vec![3, 1, 2, 0]);
+
+        fs::write(&path, "1\nfoo\n")?;
+        assert!(collect(|sink| read_ids(&path, sink)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn check_csv() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("users.csv");
+        fs::write(&path, "name,user_id\nalice,10\nbob,5\n")?;
+
+        assert_eq!(
+            collect(|sink| read_csv(&path, Some("user_id"), sink))?,
+            vec![10, 5]
+        );
+        assert!(collect(|sink| read_csv(&path, None, sink)).is_err());
+        assert!(collect(|sink| read_csv(&path, Some("missing"), sink)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn check_parquet() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("users.parquet");
+        let schema =
+            parse_message_type("message schema { REQUIRED INT64 score; REQUIRED INT64 user_id; }")?;
+        let mut writer = SerializedFileWriter::new(
+            File::create(&path)?,
+            Arc::new(schema),
+            Arc::new(WriterProperties::builder().build()),
+        )?;
+        let mut row_group = writer.next_row_group()?;
+        for values in [[-1, 0, 7], [30, 10, 20]] {
+            let mut column = row_group.next_column()?.unwrap();
+            column
+                .typed::<Int64Type>()
+                .write_batch(&values, None, None)?;
+            column.close()?;
+        }
+        row_group.close()?;
+        writer.close()?;
+
+        assert_eq!(
+            collect(|sink| read_parquet(&path, Some("user_id"), sink))?,
+            vec![30, 10, 20]
+        );
+        // неположительные идентификаторы
+        assert_eq!(
+            collect(|sink| read_parquet(&path, None, sink))?,
+            vec![0, 0, 7]
+        );
+        assert!(collect(|sink| read_parquet(&path, Some("missing"), sink)).is_err());
+        Ok(())
+    }
+}
@@ -1,14 +1,31 @@
+use anyhow::ensure;
 use clap::Parser;
 use dotenv::dotenv;
+use manifest::{delta_paths, term_path, Manifest, ReaderLease};
 use prelude::*;
-use std::path::PathBuf;
-use tindex_core::{encoding::PlainTextDecoder, PostingListDecoder};
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tindex_core::{
+    encoding::FileDecoder, segment::SegmentCache, DecodeError, Exclude, Merge, PostingList,
+    PostingListDecoder, RangePostingList,
+};
+use tokio::task::spawn_blocking;
+use views::Views;
 extern crate rocket;
 
 mod cli;
 pub mod clickhouse;
+pub mod files;
+pub mod manifest;
 pub mod mysql;
+mod planner;
+pub mod postgres;
 pub mod query;
+pub mod sqlite;
+pub mod views;
 
 pub mod prelude {
     use std::path::PathBuf;
@@ -29,19 +46,137 @@ pub mod prelude {
 
         #[error("Query worker panic")]
         QueryWorkerPanic,
+
+        #[error("Universe is not configured, complement (!) is not available")]
+        UniverseNotConfigured,
+
+        #[error("Syntax error at {line}:{column}: {message}")]
+        QuerySyntax {
+            message: String,
+            /// смещение в байтах от начала запроса
+            position: usize,
+            line: usize,
+            column: usize,
+        },
+
+        #[error("Invalid query: {0}")]
+        InvalidQuery(String),
+
+        #[error("Term not found: {0}")]
+        TermNotFound(String),
+
+        #[error("Cycle between views: {0}")]
+        ViewCycle(String),
+
+        #[error("Cycle between derived terms: {0}")]
+        DerivedCycle(String),
     }
 }
 
 pub mod config {
     use super::*;
     use cron::Schedule;
-    use serde::{de::Error, Deserialize, Deserializer};
-    use std::str::FromStr;
+    use fn_error_context::context;
+    use serde::{de::Error, Deserialize, Deserializer, Serialize};
+    use std::{fs::File, path::Path, str::FromStr};
+    use tindex_core::encoding::{block, roaring};
 
     #[derive(Deserialize, PartialEq, Eq, Debug)]
     pub struct Config {
         pub mysql: Option<Vec<mysql::MySqlDatabase>>,
         pub clickhouse: Option<Vec<clickhouse::ClickhouseDatabase>>,
+        pub postgres: Option<Vec<postgres::PostgresDatabase>>,
+        pub sqlite: Option<Vec<sqlite::SqliteDatabase>>,
+        pub files: Option<Vec<files::FilesDatabase>>,
+        #[serde(default)]
+        pub views: Views,
+        #[serde(default)]
+        pub derived: Vec<DerivedTerm>,
+    }
+
+    /// Терм, который индексатор вычисляет по выражению над другими термами индекса
+    ///
+    /// Терм пересчитывается по расписанию, а также при появлении нового поколения любого из термов,
+    /// используемых выражением.
+    #[derive(Deserialize, PartialEq, Eq, Debug, Clone)]
+    pub struct DerivedTerm {
+        pub name: String,
+        #[serde(deserialize_with = "schedule_from_string")]
+        pub schedule: Schedule,
+        pub query: String,
+        #[serde(default)]
+        pub format: Format,
+    }
+
+    /// Запросы для инкрементального обновления терма
+    ///
+    /// Вместо полного перестроения терма индексатор запрашивает только изменения с момента предыдущего
+    /// обновления и сохраняет их в виде дельты – файлов добавленных и удаленных идентификаторов. Момент
+    /// обновления (watermark) – число, монотонно растущее с каждым изменением данных (например, номер
+    /// последней записи в журнале изменений). В запросах `additions` и `removals` подстрока `{watermark}`
+    /// заменяется значением, на момент которого был построен терм, а подстрока `{until}` – значением,
+    /// прочитанным перед выполнением дельты. Запросы должны ограничивать изменения полуинтервалом
+    /// `({watermark}, {until}]`, иначе изменения, сделанные после чтения watermark'а, будут повторно
+    /// получены следующей дельтой.
+    ///
+    /// Запросы должны возвращать итоговое состояние идентификатора на момент `{until}`: `additions` –
+    /// измененные идентификаторы, входящие в терм, `removals` – измененные идентификаторы, не входящие в
+    /// него. Идентификатор, удаленный и снова добавленный в пределах одного окна, должен быть возвращен
+    /// только запросом `additions`. Если идентификатор возвращен обоими запросами, дельта не публикуется.
+    #[derive(Deserialize, PartialEq, Eq, Debug, Clone)]
+    pub struct DeltaQuery {
+        /// запрос, возвращающий текущее значение watermark'а (одно положительное число)
+        pub watermark: String,
+        /// идентификаторы, которые должны быть добавлены в терм
+        pub additions: String,
+        /// идентификаторы, которые должны быть удалены из терма
+        pub removals: String,
+        /// количество дельт, после которого терм перестраивается в один файл
+        #[serde(default = "default_compact_after")]
+        pub compact_after: usize,
+    }
+
+    fn default_compact_after() -> usize {
+        10
+    }
+
+    impl DeltaQuery {
+        /// Подставляет границы окна изменений `(watermark, until]` в текст запроса
+        pub fn bind(sql: &str, watermark: u64, until: u64) -> String {
+            sql.replace("{watermark}", &watermark.to_string())
+                .replace("{until}", &until.to_string())
+        }
+    }
+
+    impl Config {
+        #[context("Reading config: {}", path.display())]
+        pub fn read(path: &Path) -> Result<Self> {
+            let file = File::open(path)?;
+            let config = serde_yaml::from_reader(file)?;
+            Ok(config)
+        }
+    }
+
+    /// Формат в котором индексатор сохраняет результаты запроса
+    #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Format {
+        /// Блочный формат с дельта-кодированием (см. [`tindex_core::encoding::block`])
+        #[default]
+        Block,
+
+        /// Контейнерный формат для плотных множеств (см. [`tindex_core::encoding::roaring`])
+        Roaring,
+    }
+
+    impl Format {
+        /// Версия бинарного формата, которой соответствуют записываемые файлы
+        pub fn version(self) -> u32 {
+            match self {
+                Format::Block => block::VERSION,
+                Format::Roaring => roaring::VERSION,
+            }
+        }
     }
 
     pub fn schedule_from_string<'de, D>(deserializer: D) -> std::result::Result<Schedule, D::Error>
@@ -62,13 +197,72 @@ pub mod config {
     pub trait Query: Clone {
         fn name(&self) -> &str;
         fn schedule(&self) -> &cron::Schedule;
+        fn format(&self) -> Format;
+        fn sql(&self) -> &str;
+
+        /// Файл, при изменении которого запрос выполняется вне расписания
+        fn watched_file(&self) -> Option<&Path> {
+            None
+        }
+
+        /// Запросы инкрементального обновления терма, если источник их поддерживает
+        fn delta(&self) -> Option<&DeltaQuery> {
+            None
+        }
     }
 
     pub trait Connection {
         type Query: Query;
 
         fn name(&self) -> &str;
-        fn execute(&mut self, query: &Self::Query) -> Result<Vec<u64>>;
+
+        /// Выполняет запрос и передает идентификаторы в `sink` по мере получения
+        ///
+        /// Идентификаторы не обязаны быть упорядочены. Результат запроса не накапливается в памяти, поэтому
+        /// реализации должны по возможности читать его из БД потоком.
+        ///
+        /// Значения, которые не могут быть идентификаторами (отрицательные числа в знаковых колонках),
+        /// передаются в `sink` как 0 (см. [`to_id`]). Индексатор пропускает такие строки вместе с 0 и
+        /// [`NO_DOC`](tindex_core::NO_DOC) и сообщает их количество, а не завершает запрос ошибкой.
+        fn execute(
+            &mut self,
+            query: &Self::Query,
+            sink: &mut dyn FnMut(u64) -> Result<()>,
+        ) -> Result<()>;
+
+        /// Выполняет произвольный SQL-запрос, первая колонка результата которого – число
+        ///
+        /// Используется для инкрементального обновления термов ([`DeltaQuery`]).
+        fn execute_sql(
+            &mut self,
+            sql: &str,
+            _sink: &mut dyn FnMut(u64) -> Result<()>,
+        ) -> Result<()> {
+            anyhow::bail!(
+                "Database {} doesn't support SQL queries: {}",
+                self.name(),
+                sql
+            )
+        }
+    }
+
+    /// Преобразует значение знаковой колонки в идентификатор
+    ///
+    /// Неположительные значения не могут быть идентификаторами и преобразуются в 0, который индексатор
+    /// пропускает как недопустимый идентификатор.
+    pub fn to_id(value: i64) -> u64 {
+        u64::try_from(value).unwrap_or(0)
+    }
+
+    /// Выполняет запрос и возвращает идентификаторы в порядке получения
+    #[cfg(test)]
+    pub(crate) fn collect<C: Connection>(conn: &mut C, query: &C::Query) -> Result<Vec<u64>> {
+        let mut ids = vec![];
+        conn.execute(query, &mut |id| {
+            ids.push(id);
+            Ok(())
+        })?;
+        Ok(ids)
     }
 }
 
@@ -76,16 +270,219 @@ pub trait Index: Send + Sync {
     type Iterator: PostingListDecoder + 'static;
 
     fn lookup(&self, name: &str) -> Result<Self::Iterator>;
+
+    /// Универсальное множество, относительно которого вычисляется дополнение (`!expr`)
+    fn universe(&self) -> Result<PostingList>;
+
+    /// Именованные запросы, которые могут использоваться в запросах наравне с термами
+    fn views(&self) -> &Views {
+        static NO_VIEWS: Views = Views::EMPTY;
+        &NO_VIEWS
+    }
+
+    /// Количество элементов в терме, если оно известно. Используется планировщиком запросов
+    fn cardinality(&self, _name: &str) -> Option<u64> {
+        None
+    }
+
+    /// Количество элементов в универсальном множестве, если оно известно
+    fn universe_cardinality(&self) -> Option<u64> {
+        None
+    }
+
+    /// Универсальное множество содержит все термы индекса
+    ///
+    /// Только в этом случае `a & !b` эквивалентно `a - b` и планировщик может не обращаться к универсальному
+    /// множеству. Диапазон идентификаторов ([`Universe::Range`]) может не содержать элементы термов.
+    fn universe_contains_terms(&self) -> bool {
+        false
+    }
 }
 
-pub struct DirectoryIndex(pub PathBuf);
+/// Универсальное множество индекса
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Universe {
+    /// Терм индекса, содержащий все идентификаторы
+    Term(String),
 
-impl Index for DirectoryIndex {
-    type Iterator = PlainTextDecoder;
+    /// Диапазон идентификаторов (`1..1000000`)
+    Range(Range<u64>),
+}
+
+impl FromStr for Universe {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some((start, end)) = s.split_once("..") {
+            let range = start.trim().parse()?..end.trim().parse()?;
+            ensure!(
+                range.start > 0 && range.start < range.end,
+                "Invalid universe range: {}",
+                s
+            );
+            Ok(Universe::Range(range))
+        } else {
+            Ok(Universe::Term(s.to_string()))
+        }
+    }
+}
+
+/// Индекс в виде директории с файлами термов и [манифестом](manifest)
+///
+/// Файлы отображаются в память и разделяются между всеми запросами к индексу. Запросы выполняются
+/// над [`IndexSnapshot`], полученным через [`DirectoryIndex::snapshot`].
+pub struct DirectoryIndex {
+    path: PathBuf,
+    segments: SegmentCache,
+    universe: Option<Universe>,
+    views: Views,
+}
+
+impl DirectoryIndex {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            segments: SegmentCache::default(),
+            universe: None,
+            views: Views::default(),
+        }
+    }
+
+    pub fn with_universe(self, universe: Option<Universe>) -> Self {
+        Self { universe, ..self }
+    }
+
+    pub fn with_views(self, views: Views) -> Self {
+        Self { views, ..self }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Возвращает снимок индекса, все термы которого соответствуют одной версии манифеста
+    ///
+    /// Файлы термов снимка не удаляются индексатором, пока снимок существует.
+    pub fn snapshot(&self) -> Result<IndexSnapshot<'_>> {
+        let (lease, manifest) = ReaderLease::acquire(&self.path)?;
+        Ok(IndexSnapshot {
+            index: self,
+            manifest,
+            _lease: lease,
+        })
+    }
+}
+
+pub struct IndexSnapshot<'a> {
+    index: &'a DirectoryIndex,
+    manifest: Manifest,
+    _lease: ReaderLease,
+}
+
+impl IndexSnapshot<'_> {
+    /// Версия манифеста, которой соответствует снимок
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    fn open(&self, path: PathBuf) -> Result<FileDecoder> {
+        self.index
+            .segments
+            .get(&path)
+            .and_then(FileDecoder::new)
+            .context(OpeningIndexFile(path))
+    }
+}
+
+impl Index for IndexSnapshot<'_> {
+    type Iterator = TermDecoder;
 
     fn lookup(&self, name: &str) -> Result<Self::Iterator> {
-        let path = self.0.join(format!("{}.idx", name));
-        PlainTextDecoder::open(&path).context(OpeningIndexFile(path))
+        let Some(term) = self.manifest.terms.get(name) else {
+            // индексы построенные до появления манифеста
+            let path = self.index.path.join(format!("{}.idx", name));
+            if !path.exists() {
+                return Err(TermNotFound(name.to_string()).into());
+            }
+            return Ok(TermDecoder::Base(self.open(path)?));
+        };
+        let base = self.open(term_path(&self.index.path, name, term.generation))?;
+        term.deltas
+            .iter()
+            .try_fold(TermDecoder::Base(base), |list, delta| {
+                let (additions, removals) = delta_paths(&self.index.path, name, delta.generation);
+                let merged = Merge(list.into(), self.open(additions)?.into());
+                Ok(TermDecoder::Delta(Exclude(
+                    merged.into(),
+                    self.open(removals)?.into(),
+                )))
+            })
+    }
+
+    fn universe(&self) -> Result<PostingList> {
+        match &self.index.universe {
+            Some(Universe::Term(name)) => Ok(self.lookup(name)?.into()),
+            Some(Universe::Range(range)) => Ok(RangePostingList::new(range.clone()).into()),
+            None => Err(UniverseNotConfigured.into()),
+        }
+    }
+
+    fn views(&self) -> &Views {
+        &self.index.views
+    }
+
+    fn cardinality(&self, name: &str) -> Option<u64> {
+        // удаленные в дельтах идентификаторы не вычитаются: оценка сверху достаточна для планировщика
+        let term = self.manifest.terms.get(name)?;
+        Some(term.records + term.deltas.iter().map(|d| d.additions).sum::<u64>())
+    }
+
+    fn universe_cardinality(&self) -> Option<u64> {
+        match self.index.universe.as_ref()? {
+            Universe::Term(name) => self.cardinality(name),
+            Universe::Range(range) => Some(range.end - range.start),
+        }
+    }
+
+    fn universe_contains_terms(&self) -> bool {
+        matches!(self.index.universe, Some(Universe::Term(_)))
+    }
+}
+
+/// Posting list терма: файл терма или файл терма с примененными к нему дельтами
+#[allow(clippy::large_enum_variant)]
+pub enum TermDecoder {
+    Base(FileDecoder),
+    Delta(Exclude),
+}
+
+impl PostingListDecoder for TermDecoder {
+    fn next_batch_advance(&mut self, target: u64, buffer: &mut [u64]) -> usize {
+        match self {
+            Self::Base(list) => list.next_batch_advance(target, buffer),
+            Self::Delta(list) => list.next_batch_advance(target, buffer),
+        }
+    }
+
+    fn next_batch(&mut self, buffer: &mut [u64]) -> usize {
+        match self {
+            Self::Base(list) => list.next_batch(buffer),
+            Self::Delta(list) => list.next_batch(buffer),
+        }
+    }
+
+    fn count(&mut self) -> u64 {
+        match self {
+            Self::Base(list) => list.count(),
+            Self::Delta(list) => list.count(),
+        }
+    }
+
+    fn error(&self) -> Option<&DecodeError> {
+        match self {
+            Self::Base(list) => list.error(),
+            Self::Delta(list) => list.error(),
+        }
     }
 }
 
@@ -102,6 +499,8 @@ enum Subcommand {
     Index(cli::indexer::IndexOpts),
     Update(cli::indexer::UpdateOpts),
     Query(cli::query::Opts),
+    Count(cli::count::Opts),
+    Verify(cli::verify::Opts),
 }
 
 #[tokio::main]
@@ -110,10 +509,13 @@ async fn main() -> Result<()> {
     env_logger::init();
 
     match Args::parse().action {
-        Subcommand::Index(opts) => cli::indexer::do_index(opts)?,
-        Subcommand::Update(opts) => cli::indexer::do_update(opts)?,
+        // индексатор использует синхронные клиенты БД, которые нельзя вызывать из потоков tokio
+        Subcommand::Index(opts) => spawn_blocking(|| cli::indexer::do_index(opts)).await??,
+        Subcommand::Update(opts) => spawn_blocking(|| cli::indexer::do_update(opts)).await??,
         Subcommand::Serve(opts) => cli::serve::main(opts).await?,
         Subcommand::Query(opts) => cli::query::main(opts).await?,
+        Subcommand::Count(opts) => cli::count::main(opts).await?,
+        Subcommand::Verify(opts) => cli::verify::main(opts).await?,
     }
     Ok(())
 }
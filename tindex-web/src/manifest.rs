@@ -0,0 +1,424 @@
+//! Манифест индекса
+//!
+//! Манифест (файл [`MANIFEST_FILE`] в директории индекса) описывает актуальное состояние индекса: для каждого
+//! терма в нем указано поколение (generation) файла с данными, а также метаданные о том, как этот файл был
+//! построен. Файл терма с поколением `N` называется `{name}.{N}.idx` и никогда не изменяется после записи.
+//! Обновление терма – это запись файла нового поколения и атомарная замена манифеста.
+//!
+//! Терм, обновляемый инкрементально, дополнительно содержит список [дельт](Delta). Каждая дельта также
+//! получает собственное поколение `N` и состоит из файлов `{name}.{N}.add.idx` (добавленные идентификаторы)
+//! и `{name}.{N}.del.idx` (удаленные идентификаторы), которые применяются поверх файла терма по порядку.
+//!
+//! Читатели загружают манифест один раз на запрос и таким образом видят согласованный снимок всех термов.
+//! Каждое обновление манифеста увеличивает его эпоху. На время работы со снимком читатель удерживает
+//! [`ReaderLease`] – разделяемую блокировку файла `readers.{epoch}.lock` эпохи загруженного манифеста. Индексатор
+//! удаляет файлы вытесненного поколения не раньше чем через [`GC_GRACE_PERIOD`] после вытеснения и только если
+//! ни один читатель не удерживает блокировку эпохи, в которой поколение еще использовалось. Читатели новых
+//! эпох удалению не препятствуют. Уже открытые файлы отображены в память и остаются доступны читателям и после
+//! удаления.
+use crate::{config::Format, prelude::*};
+use chrono::{DateTime, Utc};
+use fn_error_context::context;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+pub const MANIFEST_FILE: &str = "manifest.yaml";
+
+/// Файл, используемый индексатором для блокировки манифеста на время обновления
+pub const LOCK_FILE: &str = "manifest.lock";
+
+/// Время, по прошествии которого файлы вытесненных поколений могут быть удалены
+pub const GC_GRACE_PERIOD: Duration = Duration::from_secs(60);
+
+/// Блокировка читателя снимка индекса, снимается при удалении
+///
+/// Пока блокировка удерживается, файлы поколений, которые использовались в эпохе загруженного читателем
+/// манифеста, не удаляются.
+pub struct ReaderLease(#[allow(dead_code)] File);
+
+impl ReaderLease {
+    /// Загружает манифест и захватывает блокировку его эпохи
+    ///
+    /// Блокировка захватывается без ожидания. Если манифест был обновлен до того, как блокировка была
+    /// захвачена, индексатор мог ее не увидеть, поэтому попытка повторяется с новым манифестом.
+    #[context("Acquiring reader lease: {}", dir.display())]
+    pub fn acquire(dir: &Path) -> Result<(Self, Manifest)> {
+        let mut manifest = Manifest::load(dir)?;
+        loop {
+            let file = open_readers_file(dir, manifest.epoch)?;
+            let locked = match file.try_lock_shared() {
+                Ok(_) => true,
+                // индексатор удаляет файл блокировки устаревшей эпохи
+                Err(TryLockError::WouldBlock) => false,
+                Err(TryLockError::Error(e)) => return Err(e.into()),
+            };
+            let current = Manifest::load(dir)?;
+            if locked && current.epoch == manifest.epoch {
+                return Ok((Self(file), manifest));
+            }
+            manifest = current;
+        }
+    }
+}
+
+/// Наименьшая эпоха, предшествующая `current`, блокировку которой удерживает хотя бы один читатель
+///
+/// Файлы блокировок предшествующих эпох, не удерживаемые читателями, удаляются: новые читатели загрузят
+/// манифест эпохи не меньше `current`.
+pub fn oldest_reader_epoch(dir: &Path, current: u64) -> Result<Option<u64>> {
+    let mut oldest = None;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(epoch) = readers_file_epoch(&path).filter(|epoch| *epoch < current) else {
+            continue;
+        };
+        let file = match OpenOptions::new().write(true).open(&path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            file => file?,
+        };
+        match file.try_lock() {
+            Ok(_) => match fs::remove_file(&path) {
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                result => result?,
+            },
+            Err(TryLockError::WouldBlock) => {
+                oldest = Some(oldest.map_or(epoch, |oldest: u64| oldest.min(epoch)))
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+    }
+    Ok(oldest)
+}
+
+fn open_readers_file(dir: &Path, epoch: u64) -> Result<File> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(format!("readers.{}.lock", epoch)))?)
+}
+
+fn readers_file_epoch(path: &Path) -> Option<u64> {
+    let name = path.file_name()?.to_str()?;
+    name.strip_prefix("readers.")?
+        .strip_suffix(".lock")?
+        .parse()
+        .ok()
+}
+
+#[derive(Serialize, Deserialize, Default, PartialEq, Eq, Debug, Clone)]
+pub struct Manifest {
+    /// Номер версии манифеста, увеличивается при каждом обновлении
+    #[serde(default)]
+    pub epoch: u64,
+
+    #[serde(default)]
+    pub terms: BTreeMap<String, Term>,
+
+    /// Поколения термов, которые больше не используются, но еще не удалены с диска
+    #[serde(default)]
+    pub retired: Vec<RetiredTerm>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct Term {
+    pub generation: u64,
+    pub records: u64,
+    pub database: String,
+    pub sql_hash: String,
+    pub built_at: DateTime<Utc>,
+    pub format: Format,
+    pub format_version: u32,
+
+    /// Поколения термов, из которых был вычислен [производный терм](crate::config::DerivedTerm)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub inputs: BTreeMap<String, u64>,
+
+    /// Значение watermark'а источника, которому соответствует терм с учетом всех дельт
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watermark: Option<u64>,
+
+    /// Инкрементальные изменения терма в порядке применения
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deltas: Vec<Delta>,
+}
+
+impl Term {
+    /// Поколение последнего изменения терма: последней дельты или, если дельт нет, файла терма
+    pub fn version(&self) -> u64 {
+        self.deltas.last().map_or(self.generation, |d| d.generation)
+    }
+
+    /// Все поколения, файлы которых составляют терм
+    pub fn generations(&self) -> impl Iterator<Item = u64> + '_ {
+        let deltas = self.deltas.iter().map(|d| d.generation);
+        std::iter::once(self.generation).chain(deltas)
+    }
+}
+
+#[cfg(test)]
+impl Term {
+    /// Терм поколения `generation` из `records` идентификаторов в формате [`Format::Block`]
+    pub fn for_test(generation: u64, records: u64) -> Self {
+        Self {
+            generation,
+            records,
+            database: "db".to_string(),
+            sql_hash: sql_hash("SELECT 1"),
+            built_at: Utc::now(),
+            format: Format::Block,
+            format_version: Format::Block.version(),
+            inputs: BTreeMap::new(),
+            watermark: None,
+            deltas: vec![],
+        }
+    }
+}
+
+/// Инкрементальное изменение терма
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct Delta {
+    pub generation: u64,
+    /// количество добавленных идентификаторов
+    pub additions: u64,
+    /// количество удаленных идентификаторов
+    pub removals: u64,
+    pub watermark: u64,
+    pub built_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct RetiredTerm {
+    pub name: String,
+    pub generation: u64,
+    pub retired_at: DateTime<Utc>,
+    /// последняя эпоха манифеста, в которой поколение использовалось
+    #[serde(default)]
+    pub epoch: u64,
+}
+
+impl Manifest {
+    /// Читает манифест из директории индекса. Отсутствие манифеста эквивалентно пустому индексу
+    #[context("Reading manifest: {}", dir.display())]
+    pub fn load(dir: &Path) -> Result<Self> {
+        match File::open(dir.join(MANIFEST_FILE)) {
+            Ok(file) => Ok(serde_yaml::from_reader(file)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Номер поколения, который должен получить следующий файл терма
+    pub fn next_generation(&self, name: &str) -> u64 {
+        let current = self.terms.get(name).map(Term::version);
+        let retired = self
+            .retired
+            .iter()
+            .filter(|r| r.name == name)
+            .map(|r| r.generation);
+        current.into_iter().chain(retired).max().unwrap_or(0) + 1
+    }
+
+    /// Делает `term` актуальным поколением терма `name`, предыдущее поколение (вместе с его дельтами)
+    /// помечается как вытесненное
+    pub fn publish(&mut self, name: &str, term: Term, now: DateTime<Utc>) {
+        if let Some(previous) = self.terms.insert(name.to_string(), term) {
+            self.retire_generations(name, &previous, now);
+        }
+    }
+
+    /// Добавляет дельту к терму `name` и сдвигает его watermark
+    ///
+    /// Возвращает `false`, если терма нет в индексе.
+    pub fn publish_delta(&mut self, name: &str, delta: Delta) -> bool {
+        let Some(term) = self.terms.get_mut(name) else {
+            return false;
+        };
+        term.watermark = Some(delta.watermark);
+        term.deltas.push(delta);
+        true
+    }
+
+    /// Исключает терм `name` из индекса, его текущее поколение помечается как вытесненное
+    ///
+    /// Поколение остается в списке вытесненных, чтобы номера поколений терма не использовались повторно.
+    pub fn retire(&mut self, name: &str, now: DateTime<Utc>) -> Option<Term> {
+        let term = self.terms.remove(name)?;
+        self.retire_generations(name, &term, now);
+        Some(term)
+    }
+
+    fn retire_generations(&mut self, name: &str, term: &Term, now: DateTime<Utc>) {
+        for generation in term.generations() {
+            self.retired.push(RetiredTerm {
+                name: name.to_string(),
+                generation,
+                retired_at: now,
+                epoch: self.epoch,
+            });
+        }
+    }
+
+    /// Удаляет из манифеста и возвращает вытесненные поколения, срок ожидания которых истек
+    ///
+    /// Поколения, которые использовались в эпохе `oldest_reader` или позже, не возвращаются: читатели этой
+    /// эпохи могут обращаться к их файлам.
+    pub fn take_expired(
+        &mut self,
+        now: DateTime<Utc>,
+        grace: Duration,
+        oldest_reader: Option<u64>,
+    ) -> Vec<RetiredTerm> {
+        let grace = chrono::Duration::from_std(grace).unwrap_or_else(|_| chrono::Duration::zero());
+        let (expired, retired) = self.retired.drain(..).partition(|r| {
+            r.retired_at + grace <= now && oldest_reader.is_none_or(|epoch| epoch > r.epoch)
+        });
+        self.retired = retired;
+        expired
+    }
+}
+
+/// Путь к файлу заданного поколения терма
+pub fn term_path(dir: &Path, name: &str, generation: u64) -> PathBuf {
+    dir.join(format!("{}.{}.idx", name, generation))
+}
+
+/// Пути к файлам добавленных и удаленных идентификаторов дельты заданного поколения
+pub fn delta_paths(dir: &Path, name: &str, generation: u64) -> (PathBuf, PathBuf) {
+    (
+        dir.join(format!("{}.{}.add.idx", name, generation)),
+        dir.join(format!("{}.{}.del.idx", name, generation)),
+    )
+}
+
+/// Пути ко всем файлам, которые могут принадлежать поколению терма
+pub fn generation_paths(dir: &Path, name: &str, generation: u64) -> [PathBuf; 3] {
+    let (additions, removals) = delta_paths(dir, name, generation);
+    [term_path(dir, name, generation), additions, removals]
+}
+
+/// Хеш текста запроса, сохраняемый в манифесте (FNV-1a)
+pub fn sql_hash(sql: &str) -> String {
+    let hash = sql.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_generations() {
+        let now = Utc::now();
+        let mut manifest = Manifest::default();
+        assert_eq!(manifest.next_generation("a"), 1);
+
+        manifest.publish("a", Term::for_test(1, 10), now);
+        manifest.publish("a", Term::for_test(2, 10), now);
+        manifest.publish("b", Term::for_test(1, 10), now);
+        assert_eq!(manifest.next_generation("a"), 3);
+        assert_eq!(manifest.next_generation("b"), 2);
+        assert_eq!(manifest.retired.len(), 1);
+
+        assert!(manifest.take_expired(now, GC_GRACE_PERIOD, None).is_empty());
+        let later = now + chrono::Duration::minutes(2);
+        // вытесненное поколение использует читатель эпохи, в которой оно было вытеснено
+        assert!(manifest
+            .take_expired(later, GC_GRACE_PERIOD, Some(0))
+            .is_empty());
+        let expired = manifest.take_expired(later, GC_GRACE_PERIOD, Some(1));
+        assert_eq!(expired.len(), 1);
+        assert_eq!((expired[0].name.as_str(), expired[0].generation), ("a", 1));
+        assert!(manifest.retired.is_empty());
+
+        assert!(manifest.retire("b", now).is_some());
+        assert!(manifest.retire("b", now).is_none());
+        assert!(!manifest.terms.contains_key("b"));
+        assert_eq!(manifest.next_generation("b"), 2);
+    }
+
+    #[test]
+    fn check_deltas() {
+        let now = Utc::now();
+        let mut manifest = Manifest::default();
+        assert!(!manifest.publish_delta("a", delta(1, 10)));
+
+        manifest.publish("a", Term::for_test(1, 10), now);
+        assert!(manifest.publish_delta("a", delta(2, 10)));
+        assert!(manifest.publish_delta("a", delta(3, 20)));
+        let a = &manifest.terms["a"];
+        assert_eq!((a.version(), a.watermark), (3, Some(20)));
+        assert_eq!(a.generations().collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(manifest.next_generation("a"), 4);
+
+        // новое поколение вытесняет файл терма вместе с дельтами
+        manifest.publish("a", Term::for_test(4, 10), now);
+        let retired = manifest.retired.iter().map(|r| r.generation);
+        assert_eq!(retired.collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(manifest.next_generation("a"), 5);
+    }
+
+    #[test]
+    fn check_reader_lease() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = dir.path();
+        let save = |manifest: &Manifest| -> Result<()> {
+            fs::write(dir.join(MANIFEST_FILE), serde_yaml::to_string(manifest)?)?;
+            Ok(())
+        };
+        let mut manifest = Manifest::default();
+        save(&manifest)?;
+
+        let (lease, loaded) = ReaderLease::acquire(dir)?;
+        let (other, _) = ReaderLease::acquire(dir)?;
+        assert_eq!(loaded.epoch, 0);
+        // блокировки текущей эпохи не учитываются
+        assert_eq!(oldest_reader_epoch(dir, 0)?, None);
+
+        manifest.epoch = 2;
+        save(&manifest)?;
+        let (newer, loaded) = ReaderLease::acquire(dir)?;
+        assert_eq!(loaded.epoch, 2);
+        assert_eq!(oldest_reader_epoch(dir, 2)?, Some(0));
+        drop(lease);
+        assert_eq!(oldest_reader_epoch(dir, 2)?, Some(0));
+        drop(other);
+
+        // блокировка неиспользуемой эпохи удалена
+        assert_eq!(oldest_reader_epoch(dir, 2)?, None);
+        assert!(!dir.join("readers.0.lock").exists());
+        assert_eq!(oldest_reader_epoch(dir, 3)?, Some(2));
+        drop(newer);
+        assert_eq!(oldest_reader_epoch(dir, 3)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn read_yaml() -> Result<()> {
+        let mut manifest = Manifest::default();
+        manifest.publish("a", Term::for_test(7, 10), Utc::now());
+        manifest.publish_delta("a", delta(8, 100));
+
+        let yaml = serde_yaml::to_string(&manifest)?;
+        assert_eq!(serde_yaml::from_str::<Manifest>(&yaml)?, manifest);
+        Ok(())
+    }
+
+    fn delta(generation: u64, watermark: u64) -> Delta {
+        Delta {
+            generation,
+            additions: 1,
+            removals: 1,
+            watermark,
+            built_at: Utc::now(),
+        }
+    }
+}
@@ -1,8 +1,8 @@
 use crate::{
-    config::{self, Connection, Database, Query},
+    config::{self, Connection, Database, DeltaQuery, Format, Query},
     prelude::*,
 };
-use ::mysql::{prelude::Queryable, Conn, Opts};
+use ::mysql::{from_row_opt, from_value_opt, prelude::Queryable, Conn, Opts, Value};
 use cron::Schedule;
 use fn_error_context::context;
 use mysql::OptsBuilder;
@@ -51,8 +51,23 @@ impl Connection for MySqlConnection {
         &self.0
     }
 
-    fn execute(&mut self, query: &MySqlQuery) -> Result<Vec<u64>> {
-        Ok(self.1.exec_map(&query.sql, (), |id| id)?)
+    fn execute(
+        &mut self,
+        query: &MySqlQuery,
+        sink: &mut dyn FnMut(u64) -> Result<()>,
+    ) -> Result<()> {
+        self.execute_sql(&query.sql, sink)
+    }
+
+    fn execute_sql(&mut self, sql: &str, sink: &mut dyn FnMut(u64) -> Result<()>) -> Result<()> {
+        for row in self.1.exec_iter(sql, ())? {
+            let id = match from_row_opt::<Value>(row?)? {
+                Value::Int(id) => config::to_id(id),
+                value => from_value_opt::<u64>(value)?,
+            };
+            sink(id)?;
+        }
+        Ok(())
     }
 }
 
@@ -62,6 +77,10 @@ pub struct MySqlQuery {
     #[serde(deserialize_with = "config::schedule_from_string")]
     schedule: Schedule,
     sql: String,
+    #[serde(default)]
+    format: Format,
+    #[serde(default)]
+    delta: Option<DeltaQuery>,
 }
 
 impl Query for MySqlQuery {
@@ -72,6 +91,18 @@ impl Query for MySqlQuery {
     fn schedule(&self) -> &Schedule {
         &self.schedule
     }
+
+    fn format(&self) -> Format {
+        self.format
+    }
+
+    fn sql(&self) -> &str {
+        &self.sql
+    }
+
+    fn delta(&self) -> Option<&DeltaQuery> {
+        self.delta.as_ref()
+    }
 }
 
 #[cfg(test)]
@@ -98,6 +129,8 @@ mod tests {
                 name: "bulletin_1_week".to_string(),
                 schedule: Schedule::from_str("0 30 9,12,15 1,15 May-Aug Mon,Wed,Fri 2018/2")?,
                 sql: "SELECT 1".to_string(),
+                format: Format::Block,
+                delta: None,
             }],
         };
         assert_eq!(config, expected);
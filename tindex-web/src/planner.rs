@@ -0,0 +1,520 @@
+//! Планирование запросов
+//!
+//! Планировщик преобразует [Ast], полученное при разборе запроса, в эквивалентное ему дерево, которое дешевле
+//! выполнить. Цепочки одинаковых коммутативных операций (`a | b | c | ...`, `a & b & c & ...`) сворачиваются
+//! в n-арные узлы, которые выполняются одним декодером ([`tindex_core::MergeN`], [`tindex_core::IntersectN`])
+//! вместо цепочки бинарных.
+//!
+//! Затем [optimize] использует количество элементов в термах ([`Index::cardinality`]), чтобы:
+//!
+//! - упорядочить операнды `&` по возрастанию размера: самый короткий список ведет пересечение, а остальные
+//!   списки только продвигаются к его элементам;
+//! - вынести исключения за пределы пересечения: `(a - b) & c` выполняется как `(a & c) - b`, а `a & !b` – как
+//!   `a - b` (без обращения к универсальному множеству). Последнее допустимо, только если универсальное
+//!   множество содержит все термы ([`Index::universe_contains_terms`]), иначе дополнение вычисляется как есть.
+//!
+//! Выбранный план можно посмотреть при помощи [explain].
+use crate::{query::Ast, Index};
+use std::fmt::Write;
+
+/// Оценка сверху количества элементов в результате узла. `None` – оценка неизвестна
+type Estimate = Option<u64>;
+
+/// Сворачивает цепочки из трех и более одинаковых операций `|` и `&` в n-арные узлы
+pub(crate) fn flatten(node: Ast) -> Ast {
+    match node {
+        Ast::Merge(..) => {
+            let mut operands = vec![];
+            collect(node, &mut operands, &|n| match n {
+                Ast::Merge(lv, rv) => Ok((*lv, *rv)),
+                n => Err(n),
+            });
+            n_ary(operands, Ast::MergeN, Ast::Merge)
+        }
+        Ast::Intersect(..) => {
+            let mut operands = vec![];
+            collect(node, &mut operands, &|n| match n {
+                Ast::Intersect(lv, rv) => Ok((*lv, *rv)),
+                n => Err(n),
+            });
+            n_ary(operands, Ast::IntersectN, Ast::Intersect)
+        }
+        Ast::Exclude(lv, rv) => Ast::Exclude(Box::new(flatten(*lv)), Box::new(flatten(*rv))),
+        Ast::Not(v) => Ast::Not(Box::new(flatten(*v))),
+        Ast::AtLeast(k, args) => Ast::AtLeast(k, args.into_iter().map(flatten).collect()),
+        Ast::MergeN(args) => Ast::MergeN(args.into_iter().map(flatten).collect()),
+        Ast::IntersectN(args) => Ast::IntersectN(args.into_iter().map(flatten).collect()),
+        Ast::Ident(_) => node,
+    }
+}
+
+/// Собирает в `operands` операнды цепочки операций, распознаваемых функцией `split`
+fn collect(node: Ast, operands: &mut Vec<Ast>, split: &dyn Fn(Ast) -> Result<(Ast, Ast), Ast>) {
+    match split(node) {
+        Ok((lv, rv)) => {
+            collect(lv, operands, split);
+            collect(rv, operands, split);
+        }
+        Err(operand) => operands.push(flatten(operand)),
+    }
+}
+
+fn n_ary(
+    mut operands: Vec<Ast>,
+    n_ary: fn(Vec<Ast>) -> Ast,
+    binary: fn(Box<Ast>, Box<Ast>) -> Ast,
+) -> Ast {
+    match operands.len() {
+        1 => operands.pop().unwrap(),
+        2 => {
+            let rv = operands.pop().unwrap();
+            let lv = operands.pop().unwrap();
+            binary(Box::new(lv), Box::new(rv))
+        }
+        _ => n_ary(operands),
+    }
+}
+
+/// Переупорядочивает операнды и выносит исключения на основании оценки размера термов
+pub(crate) fn optimize(node: Ast, index: &impl Index) -> Ast {
+    match node {
+        Ast::Intersect(lv, rv) => optimize_intersect(vec![*lv, *rv], index),
+        Ast::IntersectN(args) => optimize_intersect(args, index),
+        Ast::Merge(lv, rv) => optimize_merge(vec![*lv, *rv], index),
+        Ast::MergeN(args) => optimize_merge(args, index),
+        Ast::Exclude(lv, rv) => Ast::Exclude(
+            Box::new(optimize(*lv, index)),
+            Box::new(optimize(*rv, index)),
+        ),
+        Ast::Not(v) => Ast::Not(Box::new(optimize(*v, index))),
+        Ast::AtLeast(k, args) => {
+            Ast::AtLeast(k, args.into_iter().map(|a| optimize(a, index)).collect())
+        }
+        Ast::Ident(_) => node,
+    }
+}
+
+/// Строит пересечение `operands`
+///
+/// Исключаемые части операндов (`x - y`, `!y`) собираются в одно объединение, которое вычитается из
+/// пересечения остальных частей: `(a - b) & (c - d) & !e` = `(a & c) - (b | d | e)`. Дополнения вычитаются
+/// только если универсальное множество содержит все термы. Оставшиеся операнды упорядочиваются по
+/// возрастанию оценки размера.
+fn optimize_intersect(operands: Vec<Ast>, index: &impl Index) -> Ast {
+    let mut positive = vec![];
+    let mut negative = vec![];
+    let mut complements = vec![];
+    for operand in operands {
+        split_intersect(
+            optimize(operand, index),
+            &mut positive,
+            &mut negative,
+            &mut complements,
+        );
+    }
+    if positive.is_empty() || !index.universe_contains_terms() {
+        // дополнение без положительных операндов может быть вычислено только через универсальное множество,
+        // так же как и дополнение до множества, которое может не содержать положительные операнды
+        positive.extend(complements.drain(..).map(|v| Ast::Not(Box::new(v))));
+    }
+    negative.append(&mut complements);
+
+    let intersection = n_ary(sorted(positive, index), Ast::IntersectN, Ast::Intersect);
+    if negative.is_empty() {
+        intersection
+    } else {
+        let exclusion = optimize_merge(negative, index);
+        Ast::Exclude(Box::new(intersection), Box::new(exclusion))
+    }
+}
+
+/// Раскладывает операнд пересечения на пересекаемые, вычитаемые и дополняемые части
+fn split_intersect(
+    operand: Ast,
+    positive: &mut Vec<Ast>,
+    negative: &mut Vec<Ast>,
+    complements: &mut Vec<Ast>,
+) {
+    match operand {
+        Ast::Intersect(lv, rv) => {
+            split_intersect(*lv, positive, negative, complements);
+            split_intersect(*rv, positive, negative, complements);
+        }
+        Ast::IntersectN(args) => {
+            for arg in args {
+                split_intersect(arg, positive, negative, complements);
+            }
+        }
+        Ast::Exclude(lv, rv) => {
+            split_intersect(*lv, positive, negative, complements);
+            negative.push(*rv);
+        }
+        Ast::Not(v) => complements.push(*v),
+        operand => positive.push(operand),
+    }
+}
+
+/// Строит объединение `operands`
+///
+/// Порядок операндов объединения на стоимость не влияет, но они также упорядочиваются по оценке размера,
+/// чтобы план запроса не зависел от того, как записан запрос.
+fn optimize_merge(operands: Vec<Ast>, index: &impl Index) -> Ast {
+    let mut result = vec![];
+    for operand in operands {
+        split_merge(optimize(operand, index), &mut result);
+    }
+    n_ary(sorted(result, index), Ast::MergeN, Ast::Merge)
+}
+
+fn split_merge(operand: Ast, result: &mut Vec<Ast>) {
+    match operand {
+        Ast::Merge(lv, rv) => {
+            split_merge(*lv, result);
+            split_merge(*rv, result);
+        }
+        Ast::MergeN(args) => args.into_iter().for_each(|a| split_merge(a, result)),
+        operand => result.push(operand),
+    }
+}
+
+/// Упорядочивает узлы по возрастанию оценки размера, узлы с неизвестной оценкой идут последними
+///
+/// Сортировка устойчивая: при равных оценках сохраняется порядок, в котором операнды записаны в запросе.
+fn sorted(nodes: Vec<Ast>, index: &impl Index) -> Vec<Ast> {
+    let mut nodes = nodes
+        .into_iter()
+        .map(|n| (estimate(&n, index), n))
+        .collect::<Vec<_>>();
+    nodes.sort_by_key(|(e, _)| (e.is_none(), e.unwrap_or(0)));
+    nodes.into_iter().map(|(_, n)| n).collect()
+}
+
+/// Оценивает сверху количество элементов в результате узла
+fn estimate(node: &Ast, index: &impl Index) -> Estimate {
+    match node {
+        Ast::Ident(name) => index.cardinality(name),
+        Ast::Intersect(lv, rv) => [lv, rv]
+            .into_iter()
+            .filter_map(|a| estimate(a, index))
+            .min(),
+        Ast::IntersectN(args) => args.iter().filter_map(|a| estimate(a, index)).min(),
+        Ast::Merge(lv, rv) => sum([lv.as_ref(), rv.as_ref()], index),
+        Ast::MergeN(args) => sum(args, index),
+        Ast::Exclude(lv, _) => estimate(lv, index),
+        Ast::Not(_) => index.universe_cardinality(),
+        // каждый элемент результата входит как минимум в k операндов
+        Ast::AtLeast(k, args) => sum(args, index).map(|s| s / *k as u64),
+    }
+}
+
+fn sum<'a>(nodes: impl IntoIterator<Item = &'a Ast>, index: &impl Index) -> Estimate {
+    nodes
+        .into_iter()
+        .map(|a| estimate(a, index))
+        .try_fold(0u64, |sum, e| Some(sum.saturating_add(e?)))
+}
+
+/// Возвращает текстовое представление плана запроса с оценкой размера каждого узла
+pub(crate) fn explain(node: &Ast, index: &impl Index) -> String {
+    let mut out = String::new();
+    explain_node(node, index, 0, &mut out);
+    out
+}
+
+fn explain_node(node: &Ast, index: &impl Index, depth: usize, out: &mut String) {
+    let label = match node {
+        Ast::Ident(name) => format!("Term {}", name),
+        Ast::Intersect(..) => "Intersect".to_string(),
+        Ast::IntersectN(..) => "IntersectN".to_string(),
+        Ast::Merge(..) => "Merge".to_string(),
+        Ast::MergeN(..) => "MergeN".to_string(),
+        Ast::Exclude(..) => "Exclude".to_string(),
+        Ast::Not(..) => "Complement".to_string(),
+        Ast::AtLeast(k, _) => format!("AtLeast {}", k),
+    };
+    let rows = match estimate(node, index) {
+        Some(rows) => format!("rows<={}", rows),
+        None => "rows=?".to_string(),
+    };
+    writeln!(
+        out,
+        "{:indent$}{} [{}]",
+        "",
+        label,
+        rows,
+        indent = depth * 2
+    )
+    .unwrap();
+
+    let children: Vec<&Ast> = match node {
+        Ast::Ident(_) => vec![],
+        Ast::Intersect(lv, rv) | Ast::Merge(lv, rv) | Ast::Exclude(lv, rv) => vec![lv, rv],
+        Ast::IntersectN(args) | Ast::MergeN(args) | Ast::AtLeast(_, args) => args.iter().collect(),
+        Ast::Not(v) => vec![v],
+    };
+    for child in children {
+        explain_node(child, index, depth + 1, out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        prelude::*,
+        query::{parse, parse_query, visit},
+        Universe,
+    };
+    use std::collections::HashMap;
+    use tindex_core::{PostingList, RangePostingList, VecPostingList, NO_DOC};
+    use Ast::*;
+
+    #[test]
+    fn check_flatten() {
+        // a | b | c | d
+        let chain = Merge(merge(merge(i("a"), i("b")), i("c")), i("d"));
+        assert_eq!(
+            flatten(chain),
+            MergeN(vec![*i("a"), *i("b"), *i("c"), *i("d")])
+        );
+
+        // (a & b) & (c & d)
+        let chain = Intersect(intersect(i("a"), i("b")), intersect(i("c"), i("d")));
+        assert_eq!(
+            flatten(chain),
+            IntersectN(vec![*i("a"), *i("b"), *i("c"), *i("d")])
+        );
+
+        // бинарные операции остаются бинарными
+        let binary = Merge(i("a"), i("b"));
+        assert_eq!(flatten(binary), Merge(i("a"), i("b")));
+    }
+
+    #[test]
+    fn check_flatten_nested() {
+        // (a | b | c) & d & e - (f | g | h)
+        let merge_abc = merge(merge(i("a"), i("b")), i("c"));
+        let merge_fgh = merge(merge(i("f"), i("g")), i("h"));
+        let ast = Exclude(intersect(intersect(merge_abc, i("d")), i("e")), merge_fgh);
+
+        let expected = Exclude(
+            Box::new(IntersectN(vec![
+                MergeN(vec![*i("a"), *i("b"), *i("c")]),
+                *i("d"),
+                *i("e"),
+            ])),
+            Box::new(MergeN(vec![*i("f"), *i("g"), *i("h")])),
+        );
+        assert_eq!(flatten(ast), expected);
+    }
+
+    #[test]
+    fn check_flatten_mixed_operations() {
+        // a | b & c | d – операции разного типа не сворачиваются вместе
+        let ast = Merge(merge(i("a"), intersect(i("b"), i("c"))), i("d"));
+        let expected = MergeN(vec![*i("a"), *intersect(i("b"), i("c")), *i("d")]);
+        assert_eq!(flatten(ast), expected);
+    }
+
+    #[test]
+    fn check_intersect_order() {
+        let index = TestIndex::default();
+
+        // c(1) & b(10) & a(100)
+        let ast = flatten(Intersect(intersect(i("a"), i("b")), i("c")));
+        assert_eq!(
+            optimize(ast, &index),
+            IntersectN(vec![*i("c"), *i("b"), *i("a")])
+        );
+
+        // термы с неизвестным размером идут последними
+        let ast = Intersect(i("unknown"), i("a"));
+        assert_eq!(optimize(ast, &index), Intersect(i("a"), i("unknown")));
+
+        // размер объединения – сумма размеров операндов
+        let ast = Intersect(merge(i("b"), i("c")), i("d"));
+        assert_eq!(
+            optimize(ast, &index),
+            Intersect(merge(i("c"), i("b")), i("d"))
+        );
+    }
+
+    #[test]
+    fn check_exclusions_pushed_late() {
+        let index = TestIndex::default().with_universe(Universe::Term("all".to_string()));
+
+        // (a - b) & c
+        let ast = Intersect(exclude(i("a"), i("b")), i("c"));
+        assert_eq!(
+            optimize(ast, &index),
+            Exclude(intersect(i("c"), i("a")), i("b"))
+        );
+
+        // (a - d) & !b & c
+        let ast = flatten(Intersect(
+            intersect(exclude(i("a"), i("d")), not(i("b"))),
+            i("c"),
+        ));
+        assert_eq!(
+            optimize(ast, &index),
+            Exclude(intersect(i("c"), i("a")), merge(i("b"), i("d")))
+        );
+
+        // дополнение без положительных операндов остается дополнением
+        let ast = Intersect(not(i("a")), not(i("b")));
+        assert_eq!(optimize(ast, &index), Intersect(not(i("a")), not(i("b"))));
+
+        // диапазон идентификаторов может не содержать термы, дополнение сохраняется
+        let index = TestIndex::default().with_universe(Universe::Range(1..50));
+        let ast = flatten(Intersect(
+            intersect(exclude(i("a"), i("d")), not(i("b"))),
+            i("c"),
+        ));
+        assert_eq!(
+            optimize(ast, &index),
+            Exclude(
+                Box::new(IntersectN(vec![*i("c"), *not(i("b")), *i("a")])),
+                i("d")
+            )
+        );
+    }
+
+    #[test]
+    fn check_optimized_query_results() -> Result<()> {
+        let index = TestIndex::default().with_universe(Universe::Term("all".to_string()));
+        let cases = [
+            ("a & b & c", vec![1]),
+            ("(a - b) & d", (50..=100).collect()),
+            ("a & !b & !c", (11..=100).collect()),
+            ("(a | d) & (b | c) - c", (2..=10).collect()),
+        ];
+        for (query, expected) in cases {
+            assert_eq!(run(parse_query(query, &index)?), expected, "{}", query);
+        }
+
+        // без универсального множества дополнение не может быть вычислено
+        let error = parse_query("a & !b", &TestIndex::default()).err().unwrap();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(UniverseNotConfigured)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn check_optimized_results_with_range_universe() -> Result<()> {
+        let index = TestIndex::default().with_universe(Universe::Range(1..50));
+        for query in ["a & !b", "(a - d) & !b & c", "d & !c", "!a & !b"] {
+            let unoptimized = run(visit(flatten(parse(query)?), &index)?);
+            let optimized = run(visit(optimize(flatten(parse(query)?), &index), &index)?);
+            assert_eq!(optimized, unoptimized, "{}", query);
+        }
+        assert_eq!(
+            run(parse_query("a & !b", &index)?),
+            (11..50).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    fn run(mut list: PostingList) -> Vec<u64> {
+        std::iter::from_fn(|| Some(list.next()))
+            .take_while(|v| *v != NO_DOC)
+            .collect()
+    }
+
+    #[test]
+    fn check_explain() {
+        let index = TestIndex::default();
+        let ast = optimize(Intersect(exclude(i("a"), i("b")), i("unknown")), &index);
+        let expected = "\
+Exclude [rows<=100]
+  Intersect [rows<=100]
+    Term a [rows<=100]
+    Term unknown [rows=?]
+  Term b [rows<=10]
+";
+        assert_eq!(explain(&ast, &index), expected);
+    }
+
+    /// Индекс, в котором терм `a` содержит элементы `1..=100`, `b` – `1..=10`, `c` – `1`, `d` – `50..=150`,
+    /// `all` – `1..=200`
+    struct TestIndex {
+        terms: HashMap<&'static str, Vec<u64>>,
+        universe: Option<Universe>,
+    }
+
+    impl TestIndex {
+        fn default() -> Self {
+            let terms = [
+                ("a", (1..=100).collect()),
+                ("b", (1..=10).collect()),
+                ("c", vec![1]),
+                ("d", (50..=150).collect()),
+                ("all", (1..=200).collect()),
+            ];
+            Self {
+                terms: terms.into_iter().collect(),
+                universe: None,
+            }
+        }
+
+        fn with_universe(self, universe: Universe) -> Self {
+            Self {
+                universe: Some(universe),
+                ..self
+            }
+        }
+    }
+
+    impl Index for TestIndex {
+        type Iterator = VecPostingList;
+
+        fn lookup(&self, name: &str) -> Result<Self::Iterator> {
+            let values = self.terms.get(name).context("No such term")?;
+            Ok(VecPostingList::new(values))
+        }
+
+        fn universe(&self) -> Result<PostingList> {
+            match &self.universe {
+                Some(Universe::Term(name)) => Ok(self.lookup(name)?.into()),
+                Some(Universe::Range(range)) => Ok(RangePostingList::new(range.clone()).into()),
+                None => Err(UniverseNotConfigured.into()),
+            }
+        }
+
+        fn cardinality(&self, name: &str) -> Option<u64> {
+            self.terms.get(name).map(|values| values.len() as u64)
+        }
+
+        fn universe_cardinality(&self) -> Option<u64> {
+            match self.universe.as_ref()? {
+                Universe::Term(name) => self.cardinality(name),
+                Universe::Range(range) => Some(range.end - range.start),
+            }
+        }
+
+        fn universe_contains_terms(&self) -> bool {
+            matches!(self.universe, Some(Universe::Term(_)))
+        }
+    }
+
+    fn i(name: &str) -> Box<Ast> {
+        Box::new(Ident(name.to_string()))
+    }
+
+    fn merge(lv: Box<Ast>, rv: Box<Ast>) -> Box<Ast> {
+        Box::new(Merge(lv, rv))
+    }
+
+    fn intersect(lv: Box<Ast>, rv: Box<Ast>) -> Box<Ast> {
+        Box::new(Intersect(lv, rv))
+    }
+
+    fn exclude(lv: Box<Ast>, rv: Box<Ast>) -> Box<Ast> {
+        Box::new(Exclude(lv, rv))
+    }
+
+    fn not(v: Box<Ast>) -> Box<Ast> {
+        Box::new(Not(v))
+    }
+}
@@ -1,77 +1,226 @@
 //! Токенизация и парсинг запросов
 //!
 //! Для токенизации используется библиотека [PEST](https://github.com/pest-parser/pest).
-use crate::{prelude::*, Index};
-use anyhow::bail;
+use crate::{planner, prelude::*, views::Views, Index};
+use anyhow::{bail, ensure};
 use fn_error_context::context;
-use pest::{iterators::Pairs, Parser};
+use pest::{
+    error::{InputLocation, LineColLocation},
+    iterators::Pairs,
+    pratt_parser::{Assoc, Op, PrattParser},
+    Parser,
+};
 use pest_derive::Parser;
-use tindex_core::{Exclude, Intersect, Merge, PostingList};
+use std::collections::BTreeSet;
+use tindex_core::{
+    Complement, Exclude, Intersect, IntersectN, Merge, MergeN, PostingList, Threshold,
+};
 
 #[derive(Parser)]
 #[grammar = "grammar.pest"]
 struct QueryParser;
 
 #[derive(Debug, PartialEq)]
-enum Ast {
+pub(crate) enum Ast {
     Exclude(Box<Ast>, Box<Ast>),
     Merge(Box<Ast>, Box<Ast>),
     Intersect(Box<Ast>, Box<Ast>),
+    Not(Box<Ast>),
+    AtLeast(usize, Vec<Ast>),
+    MergeN(Vec<Ast>),
+    IntersectN(Vec<Ast>),
     Ident(String),
 }
 
 /// Выполняет парсинг запроса
 ///
 /// Возвращает [PostingList] готовый к итерации. Индивидуальные термы по имени ищутся в переданном экземпляре [Index].
+/// Повреждение индексных файлов может быть обнаружено и во время итерации, поэтому после чтения результата
+/// необходимо вызвать [`PostingList::check`].
 #[context("Parsing query: {}", query)]
 pub fn parse_query(query: &str, index: &impl Index) -> Result<PostingList> {
-    let tokens = QueryParser::parse(Rule::root, query)?;
-    visit(parse_ast(tokens)?, index)
+    let list = visit(plan(query, index)?, index)?;
+    // некоторые операторы читают первые элементы списков уже при построении
+    list.check()?;
+    Ok(list)
 }
 
-fn visit(node: Ast, index: &impl Index) -> Result<PostingList> {
+/// Возвращает план выполнения запроса в текстовом виде, не выполняя запрос
+#[context("Explaining query: {}", query)]
+pub fn explain_query(query: &str, index: &impl Index) -> Result<String> {
+    Ok(planner::explain(&plan(query, index)?, index))
+}
+
+fn plan(query: &str, index: &impl Index) -> Result<Ast> {
+    let ast = expand_views(parse(query)?, index.views())?;
+    Ok(planner::optimize(planner::flatten(ast), index))
+}
+
+/// Разбирает текст запроса в [Ast]
+pub(crate) fn parse(query: &str) -> Result<Ast> {
+    let tokens = QueryParser::parse(Rule::root, query).map_err(syntax_error)?;
+    Ok(parse_ast(tokens).map_err(|e| InvalidQuery(e.to_string()))?)
+}
+
+/// Возвращает имена термов, используемых запросом (с учетом раскрытия views)
+pub fn query_terms(query: &str, views: &Views) -> Result<BTreeSet<String>> {
+    let mut terms = BTreeSet::new();
+    collect_terms(&expand_views(parse(query)?, views)?, &mut terms);
+    Ok(terms)
+}
+
+fn collect_terms(node: &Ast, terms: &mut BTreeSet<String>) {
+    match node {
+        Ast::Ident(name) => {
+            terms.insert(name.clone());
+        }
+        Ast::Exclude(lv, rv) | Ast::Merge(lv, rv) | Ast::Intersect(lv, rv) => {
+            collect_terms(lv, terms);
+            collect_terms(rv, terms);
+        }
+        Ast::Not(v) => collect_terms(v, terms),
+        Ast::AtLeast(_, args) | Ast::MergeN(args) | Ast::IntersectN(args) => {
+            args.iter().for_each(|arg| collect_terms(arg, terms))
+        }
+    }
+}
+
+/// Заменяет имена [views](crate::views) на их разобранные выражения
+pub(crate) fn expand_views(node: Ast, views: &Views) -> Result<Ast> {
+    expand(node, views, &mut vec![])
+}
+
+/// `stack` – имена views, которые раскрываются в данный момент; повторное появление имени в нем означает цикл
+fn expand(node: Ast, views: &Views, stack: &mut Vec<String>) -> Result<Ast> {
+    let expand_box =
+        |node: Box<Ast>, stack: &mut Vec<String>| expand(*node, views, stack).map(Box::new);
+    let expand_all = |nodes: Vec<Ast>, stack: &mut Vec<String>| {
+        nodes
+            .into_iter()
+            .map(|node| expand(node, views, stack))
+            .collect::<Result<Vec<_>>>()
+    };
+    let result = match node {
+        Ast::Ident(name) => match views.get(&name) {
+            None => Ast::Ident(name),
+            Some(query) => {
+                if let Some(start) = stack.iter().position(|n| *n == name) {
+                    let path = stack[start..].join(" -> ");
+                    return Err(ViewCycle(format!("{} -> {}", path, name)).into());
+                }
+                let ast = parse(query).with_context(|| format!("Parsing view: {}", name))?;
+                stack.push(name);
+                let ast = expand(ast, views, stack)?;
+                stack.pop();
+                ast
+            }
+        },
+        Ast::Exclude(lv, rv) => Ast::Exclude(expand_box(lv, stack)?, expand_box(rv, stack)?),
+        Ast::Merge(lv, rv) => Ast::Merge(expand_box(lv, stack)?, expand_box(rv, stack)?),
+        Ast::Intersect(lv, rv) => Ast::Intersect(expand_box(lv, stack)?, expand_box(rv, stack)?),
+        Ast::Not(v) => Ast::Not(expand_box(v, stack)?),
+        Ast::AtLeast(k, args) => Ast::AtLeast(k, expand_all(args, stack)?),
+        Ast::MergeN(args) => Ast::MergeN(expand_all(args, stack)?),
+        Ast::IntersectN(args) => Ast::IntersectN(expand_all(args, stack)?),
+    };
+    Ok(result)
+}
+
+fn syntax_error(e: pest::error::Error<Rule>) -> Error {
+    let position = match e.location {
+        InputLocation::Pos(position) | InputLocation::Span((position, _)) => position,
+    };
+    let (line, column) = match e.line_col {
+        LineColLocation::Pos(line_col) | LineColLocation::Span(line_col, _) => line_col,
+    };
+    QuerySyntax {
+        message: e.variant.message().to_string(),
+        position,
+        line,
+        column,
+    }
+}
+
+pub(crate) fn visit(node: Ast, index: &impl Index) -> Result<PostingList> {
     let result: PostingList = match node {
         Ast::Ident(name) => index.lookup(&name)?.into(),
         Ast::Exclude(lv, rv) => Exclude(visit(*lv, index)?, visit(*rv, index)?).into(),
         Ast::Merge(lv, rv) => Merge(visit(*lv, index)?, visit(*rv, index)?).into(),
         Ast::Intersect(lv, rv) => Intersect(visit(*lv, index)?, visit(*rv, index)?).into(),
+        Ast::Not(v) => Complement(index.universe()?, visit(*v, index)?).into(),
+        Ast::AtLeast(k, args) => Threshold::new(k, visit_all(args, index)?).into(),
+        Ast::MergeN(args) => MergeN::new(visit_all(args, index)?).into(),
+        Ast::IntersectN(args) => IntersectN::new(visit_all(args, index)?).into(),
     };
     Ok(result)
 }
 
+fn visit_all(nodes: Vec<Ast>, index: &impl Index) -> Result<Vec<PostingList>> {
+    nodes.into_iter().map(|node| visit(node, index)).collect()
+}
+
+/// Строит [Ast] из последовательности токенов
+///
+/// Операторы разбираются методом precedence climbing ([PrattParser]) в соответствии с приоритетами:
+///
+/// 1. `!` (унарный, наивысший приоритет);
+/// 2. `&`;
+/// 3. `|` и `-` (одинаковый приоритет).
+///
+/// Все бинарные операторы левоассоциативны: `a - b - c` эквивалентно `(a - b) - c`.
 fn parse_ast(input: Pairs<Rule>) -> Result<Ast> {
-    let mut expr = None;
-    let mut op = None;
-
-    for pair in input {
-        if let Rule::OP = pair.as_rule() {
-            op = Some(pair.as_str().to_string());
-        } else {
-            let rv = match pair.as_rule() {
-                Rule::expression | Rule::root => parse_ast(pair.into_inner())?,
-                Rule::ident => Ast::Ident(pair.as_str().to_string()),
-                Rule::EOI => break,
-                s => bail!("expression or ident expected, {:?} found", s),
-            };
-            expr = if let Some(lv) = expr {
-                let lv = Box::new(lv);
-                let rv = Box::new(rv);
-                match op.as_ref().map(String::as_ref) {
-                    Some("&") => Some(Ast::Intersect(lv, rv)),
-                    Some("|") => Some(Ast::Merge(lv, rv)),
-                    Some("-") => Some(Ast::Exclude(lv, rv)),
-                    _ => bail!("Invalid index operation"),
-                }
-            } else {
-                Some(rv)
+    pratt_parser()
+        .map_primary(|pair| match pair.as_rule() {
+            Rule::expression => parse_ast(pair.into_inner()),
+            // SOI ~ expression ~ EOI
+            Rule::root => match pair.into_inner().next() {
+                Some(expression) => parse_ast(expression.into_inner()),
+                None => bail!("No expression found"),
+            },
+            Rule::ident => Ok(Ast::Ident(pair.as_str().to_string())),
+            Rule::atleast => parse_atleast(pair.into_inner()),
+            s => bail!("expression or ident expected, {:?} found", s),
+        })
+        .map_prefix(|op, rv| match op.as_rule() {
+            Rule::not => Ok(Ast::Not(Box::new(rv?))),
+            s => bail!("Invalid unary operation: {:?}", s),
+        })
+        .map_infix(|lv, op, rv| {
+            let lv = Box::new(lv?);
+            let rv = Box::new(rv?);
+            match op.as_rule() {
+                Rule::intersect => Ok(Ast::Intersect(lv, rv)),
+                Rule::merge => Ok(Ast::Merge(lv, rv)),
+                Rule::exclude => Ok(Ast::Exclude(lv, rv)),
+                s => bail!("Invalid index operation: {:?}", s),
             }
-        }
-    }
-    if let Some(e) = expr {
-        Ok(e)
-    } else {
-        bail!("No expression found")
-    }
+        })
+        .parse(input)
+}
+
+/// Разбирает аргументы функции `atleast(k, expr1, expr2, ...)`
+fn parse_atleast(mut input: Pairs<Rule>) -> Result<Ast> {
+    let k = match input.next() {
+        Some(number) => number.as_str().parse::<usize>()?,
+        None => bail!("atleast() threshold expected"),
+    };
+    let args = input
+        .map(|expression| parse_ast(expression.into_inner()))
+        .collect::<Result<Vec<_>>>()?;
+    ensure!(
+        k >= 1 && k <= args.len(),
+        "atleast() threshold should be between 1 and {}, {} found",
+        args.len(),
+        k
+    );
+    Ok(Ast::AtLeast(k, args))
+}
+
+fn pratt_parser() -> PrattParser<Rule> {
+    PrattParser::new()
+        .op(Op::infix(Rule::merge, Assoc::Left) | Op::infix(Rule::exclude, Assoc::Left))
+        .op(Op::infix(Rule::intersect, Assoc::Left))
+        .op(Op::prefix(Rule::not))
 }
 
 #[cfg(test)]
@@ -87,15 +236,99 @@ mod tests {
             Box::new(Ast::Ident("b1".to_string())),
         );
         let merge = Ast::Merge(
-            Box::new(Ast::Ident("u1".to_string())),
             Box::new(Ast::Merge(
+                Box::new(Ast::Ident("u1".to_string())),
                 Box::new(Ast::Ident("a1".to_string())),
-                Box::new(Ast::Ident("u3".to_string())),
             )),
+            Box::new(Ast::Ident("u3".to_string())),
         );
         let expected = Ast::Exclude(Box::new(intersect), Box::new(merge));
 
         assert_eq!(parse_ast(tokens)?, expected);
         Ok(())
     }
+
+    #[test]
+    fn check_precedence() -> Result<()> {
+        use Ast::*;
+
+        let cases = [
+            // левая ассоциативность
+            ("a - b - c", Exclude(exclude(i("a"), i("b")), i("c"))),
+            ("a | b | c", Merge(merge(i("a"), i("b")), i("c"))),
+            ("a & b & c", Intersect(intersect(i("a"), i("b")), i("c"))),
+            ("a | b - c", Exclude(merge(i("a"), i("b")), i("c"))),
+            ("a - b | c", Merge(exclude(i("a"), i("b")), i("c"))),
+            // & связывает сильнее | и -
+            ("a | b & c", Merge(i("a"), intersect(i("b"), i("c")))),
+            ("a & b | c", Merge(intersect(i("a"), i("b")), i("c"))),
+            ("a - b & c", Exclude(i("a"), intersect(i("b"), i("c")))),
+            ("a & b - c", Exclude(intersect(i("a"), i("b")), i("c"))),
+            // скобки
+            ("a - (b - c)", Exclude(i("a"), exclude(i("b"), i("c")))),
+            ("(a | b) & c", Intersect(merge(i("a"), i("b")), i("c"))),
+            // ! связывает сильнее всех
+            ("!a & b", Intersect(not(i("a")), i("b"))),
+            ("a | !b & c", Merge(i("a"), intersect(not(i("b")), i("c")))),
+            ("!!a", Not(not(i("a")))),
+            // atleast() – обычный операнд
+            (
+                "atleast(2, a, b | c, !d) & e",
+                Intersect(
+                    Box::new(AtLeast(
+                        2,
+                        vec![*i("a"), *merge(i("b"), i("c")), *not(i("d"))],
+                    )),
+                    i("e"),
+                ),
+            ),
+            // идентификатор, начинающийся с atleast
+            ("atleast_users", *i("atleast_users")),
+        ];
+
+        for (query, expected) in cases {
+            let tokens = QueryParser::parse(Rule::root, query)?;
+            assert_eq!(parse_ast(tokens)?, expected, "{}", query);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn check_atleast_threshold() -> Result<()> {
+        for query in ["atleast(0, a, b)", "atleast(3, a, b)"] {
+            let tokens = QueryParser::parse(Rule::root, query)?;
+            assert!(parse_ast(tokens).is_err(), "{}", query);
+        }
+        Ok(())
+    }
+
+    fn i(name: &str) -> Box<Ast> {
+        Box::new(Ast::Ident(name.to_string()))
+    }
+
+    fn not(v: Box<Ast>) -> Box<Ast> {
+        Box::new(Ast::Not(v))
+    }
+
+    fn exclude(lv: Box<Ast>, rv: Box<Ast>) -> Box<Ast> {
+        Box::new(Ast::Exclude(lv, rv))
+    }
+
+    fn merge(lv: Box<Ast>, rv: Box<Ast>) -> Box<Ast> {
+        Box::new(Ast::Merge(lv, rv))
+    }
+
+    fn intersect(lv: Box<Ast>, rv: Box<Ast>) -> Box<Ast> {
+        Box::new(Ast::Intersect(lv, rv))
+    }
+
+    #[test]
+    fn parse_not() -> Result<()> {
+        let tokens = QueryParser::parse(Rule::root, "!a & !(b | !c)")?;
+
+        let expected = Ast::Intersect(not(i("a")), not(merge(i("b"), not(i("c")))));
+
+        assert_eq!(parse_ast(tokens)?, expected);
+        Ok(())
+    }
 }
@@ -0,0 +1,172 @@
+use crate::{
+    config::{self, Connection, Database, DeltaQuery, Format, Query},
+    prelude::*,
+};
+use cron::Schedule;
+use fn_error_context::context;
+use rusqlite::OpenFlags;
+use serde::Deserialize;
+use std::path::PathBuf;
+
+/// База данных SQLite в локальном файле
+///
+/// Позволяет запускать индексатор без сервера БД: при локальной разработке и в тестах.
+#[derive(Deserialize, PartialEq, Eq, Debug)]
+pub struct SqliteDatabase {
+    name: String,
+    path: PathBuf,
+    queries: Vec<SqliteQuery>,
+}
+
+impl Database for SqliteDatabase {
+    type Connection = SqliteConnection;
+
+    #[context("Opening SQLite database: {}", self.path.display())]
+    fn connect(&self) -> Result<Self::Connection> {
+        let conn =
+            rusqlite::Connection::open_with_flags(&self.path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        Ok(SqliteConnection(self.name.to_owned(), conn))
+    }
+
+    fn list_queries(&self) -> &[SqliteQuery] {
+        &self.queries[..]
+    }
+}
+
+pub struct SqliteConnection(String, rusqlite::Connection);
+
+impl Connection for SqliteConnection {
+    type Query = SqliteQuery;
+
+    fn name(&self) -> &str {
+        &self.0
+    }
+
+    fn execute(
+        &mut self,
+        query: &SqliteQuery,
+        sink: &mut dyn FnMut(u64) -> Result<()>,
+    ) -> Result<()> {
+        self.execute_sql(&query.sql, sink)
+    }
+
+    fn execute_sql(&mut self, sql: &str, sink: &mut dyn FnMut(u64) -> Result<()>) -> Result<()> {
+        let mut statement = self.1.prepare(sql)?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            sink(config::to_id(row.get(0)?))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct SqliteQuery {
+    name: String,
+    #[serde(deserialize_with = "config::schedule_from_string")]
+    schedule: Schedule,
+    sql: String,
+    #[serde(default)]
+    format: Format,
+    #[serde(default)]
+    delta: Option<DeltaQuery>,
+}
+
+impl Query for SqliteQuery {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    fn format(&self) -> Format {
+        self.format
+    }
+
+    fn sql(&self) -> &str {
+        &self.sql
+    }
+
+    fn delta(&self) -> Option<&DeltaQuery> {
+        self.delta.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use tempfile::tempdir;
+
+    #[test]
+    fn read_yaml() -> Result<()> {
+        let config: SqliteDatabase = serde_yaml::from_str(
+            r#"
+            name: local
+            path: /tmp/users.db
+            queries:
+            - name: active
+              schedule: "0 0 * * * *"
+              sql: SELECT id FROM users WHERE active
+              delta:
+                watermark: SELECT max(id) FROM changes
+                additions: SELECT user_id FROM changes WHERE id > {watermark} AND active
+                removals: SELECT user_id FROM changes WHERE id > {watermark} AND NOT active
+            "#,
+        )?;
+        let expected = SqliteDatabase {
+            name: "local".to_string(),
+            path: PathBuf::from("/tmp/users.db"),
+            queries: vec![SqliteQuery {
+                name: "active".to_string(),
+                schedule: Schedule::from_str("0 0 * * * *")?,
+                sql: "SELECT id FROM users WHERE active".to_string(),
+                format: Format::Block,
+                delta: Some(DeltaQuery {
+                    watermark: "SELECT max(id) FROM changes".to_string(),
+                    additions: "SELECT user_id FROM changes WHERE id > {watermark} AND active"
+                        .to_string(),
+                    removals: "SELECT user_id FROM changes WHERE id > {watermark} AND NOT active"
+                        .to_string(),
+                    compact_after: 10,
+                }),
+            }],
+        };
+        assert_eq!(config, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn check_execute() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("test.db");
+        let db = rusqlite::Connection::open(&path)?;
+        db.execute_batch(
+            "CREATE TABLE users (id INTEGER, active BOOLEAN);
+             INSERT INTO users VALUES (3, 1), (1, 1), (2, 0), (-1, 0);",
+        )?;
+
+        let db = SqliteDatabase {
+            name: "test".to_string(),
+            path,
+            queries: vec![],
+        };
+        let mut conn = db.connect()?;
+        let query = |sql: &str| SqliteQuery {
+            name: "test".to_string(),
+            schedule: Schedule::from_str("0 0 * * * *").unwrap(),
+            sql: sql.to_string(),
+            format: Format::Block,
+            delta: None,
+        };
+
+        let ids = config::collect(&mut conn, &query("SELECT id FROM users WHERE active"))?;
+        assert_eq!(ids, vec![3, 1]);
+        // отрицательные значения передаются как недопустимый идентификатор 0
+        let ids = config::collect(&mut conn, &query("SELECT id FROM users"))?;
+        assert_eq!(ids, vec![3, 1, 2, 0]);
+        Ok(())
+    }
+}
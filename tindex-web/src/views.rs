@@ -0,0 +1,104 @@
+//! Именованные запросы (views)
+//!
+//! В секции `views` конфигурации часто используемым выражениям можно дать имя:
+//!
+//! ```yaml
+//! views:
+//!   active_mobile: (registered_last_30_days - visits_last_7_days) & mobile
+//!   active_mobile_paid: active_mobile & paid
+//! ```
+//!
+//! В запросах имя view используется так же как имя терма. Перед планированием запроса каждое такое имя
+//! заменяется на разобранное выражение view, поэтому планировщик видит запрос целиком. Если имя view совпадает
+//! с именем терма, используется view.
+use crate::{
+    prelude::*,
+    query::{expand_views, Ast},
+};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+#[derive(Deserialize, Default, PartialEq, Eq, Debug, Clone)]
+#[serde(transparent)]
+pub struct Views(BTreeMap<String, String>);
+
+impl Views {
+    pub const EMPTY: Views = Views(BTreeMap::new());
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+
+    /// Проверяет, что выражения всех views корректны и views не ссылаются друг на друга циклически
+    pub fn validate(&self) -> Result<()> {
+        for name in self.0.keys() {
+            expand_views(Ast::Ident(name.clone()), self)?;
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize> From<[(&str, &str); N]> for Views {
+    fn from(views: [(&str, &str); N]) -> Self {
+        let views = views
+            .into_iter()
+            .map(|(name, query)| (name.to_string(), query.to_string()));
+        Self(views.collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::parse;
+
+    #[test]
+    fn read_yaml() -> Result<()> {
+        let views: Views = serde_yaml::from_str(
+            r#"
+            active_mobile: (registered - visits) & mobile
+            paid_mobile: active_mobile & paid
+            "#,
+        )?;
+        let expected = Views::from([
+            ("active_mobile", "(registered - visits) & mobile"),
+            ("paid_mobile", "active_mobile & paid"),
+        ]);
+        assert_eq!(views, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn check_expansion() -> Result<()> {
+        let views = Views::from([
+            ("active_mobile", "(registered - visits) & mobile"),
+            ("paid_mobile", "active_mobile & paid"),
+        ]);
+        views.validate()?;
+
+        let expanded = expand_views(parse("paid_mobile | other")?, &views)?;
+        assert_eq!(
+            expanded,
+            parse("((registered - visits) & mobile) & paid | other")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_cycles() -> Result<()> {
+        let views = Views::from([("a", "b & x"), ("b", "c | y"), ("c", "!a"), ("d", "d")]);
+        let error = expand_views(parse("x & a")?, &views).err().unwrap();
+        assert!(
+            matches!(error.downcast_ref(), Some(ViewCycle(path)) if path == "a -> b -> c -> a"),
+            "{:#}",
+            error
+        );
+
+        let error = expand_views(parse("d")?, &views).err().unwrap();
+        assert!(matches!(error.downcast_ref(), Some(ViewCycle(path)) if path == "d -> d"));
+
+        assert!(views.validate().is_err());
+        assert!(Views::from([("a", "b &")]).validate().is_err());
+        Ok(())
+    }
+}